@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::{Component, Path};
+use protocol::permissions::Permission;
+
+const ANY_USER: &str = "*";
+const USER_PLACEHOLDER: &str = "{user}";
+
+#[derive(Debug)]
+pub enum AccessError {
+    ReadOnly,
+    OutsidePathPrefix,
+    OutsideRoot,
+    ReservedName,
+    Denied(Permission, String),
+}
+
+impl From<AccessError> for Error {
+    fn from(error: AccessError) -> Error {
+        match error {
+            AccessError::ReadOnly => Error::other("Access is read-only"),
+            AccessError::OutsidePathPrefix => Error::other("Path is outside the allowed prefix"),
+            AccessError::OutsideRoot => Error::other("Path resolves outside the storage root"),
+            AccessError::ReservedName => Error::other("Path uses a name reserved for partial uploads"),
+            AccessError::Denied(permission, path) => {
+                Error::other(format!("Permission {} denied for {}", permission.get_name(), path))
+            }
+        }
+    }
+}
+
+// Path components relative to the user's root, "." and empty parts dropped and ".." folded
+fn split_path(path: &Path) -> Vec<String> {
+    let mut components = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => components.push(name.to_string_lossy().into_owned()),
+            Component::ParentDir => { components.pop(); },
+            _ => (),
+        }
+    }
+    components
+}
+
+#[derive(Clone)]
+struct Rule {
+    users: Vec<String>,
+    prefix: Vec<String>,
+    allow: Vec<Permission>,
+    deny: Vec<Permission>,
+}
+
+impl Rule {
+    fn applies(&self, user: &str, permission: Permission, path: &[String]) -> bool {
+        self.users.iter().any(|rule_user| rule_user == ANY_USER || rule_user == user)
+            && path.starts_with(&self.prefix)
+            && (self.allow.contains(&permission) || self.deny.contains(&permission))
+    }
+}
+
+#[derive(Clone)]
+pub struct AccessPolicy {
+    // Relative to the storage root, {user} is replaced by the user name
+    home: Option<String>,
+    roots: HashMap<String, String>,
+    rules: Vec<Rule>,
+    default_allow: bool,
+}
+
+impl AccessPolicy {
+    pub fn new(default_allow: bool) -> Self {
+        AccessPolicy { home: None, roots: HashMap::new(), rules: Vec::new(), default_allow }
+    }
+
+    pub fn set_home(&mut self, home: String) { self.home = Some(home); }
+    pub fn set_root(&mut self, user: String, root: String) { self.roots.insert(user, root); }
+
+    pub fn add_rule(&mut self, users: Vec<String>, path: &str, allow: Vec<Permission>, deny: Vec<Permission>) {
+        self.rules.push(Rule { users, prefix: split_path(Path::new(path)), allow, deny });
+    }
+
+    // None keeps the user on the storage root
+    pub fn get_root(&self, user: &str) -> Option<String> {
+        match self.roots.get(user) {
+            Some(root) => Some(root.clone()),
+            None => self.home.as_ref().map(|home| home.replace(USER_PLACEHOLDER, user)),
+        }
+    }
+
+    // Only then may a followed symlink lead out of the root
+    pub fn is_unrestricted(&self, user: &str) -> bool {
+        self.rules.is_empty() && self.default_allow && self.get_root(user).is_none()
+    }
+
+    // The most specific matching prefix decides, deny wins between rules of the same prefix
+    pub fn check(&self, user: &str, permission: Permission, path: &Path) -> Result<(), AccessError> {
+        let components = split_path(path);
+        let mut decision: Option<(usize, bool)> = None;
+        for rule in self.rules.iter().filter(|rule| rule.applies(user, permission, &components)) {
+            let allowed = !rule.deny.contains(&permission);
+            decision = match decision {
+                Some((length, current)) if length > rule.prefix.len() => Some((length, current)),
+                Some((length, current)) if length == rule.prefix.len() => Some((length, current && allowed)),
+                _ => Some((rule.prefix.len(), allowed)),
+            };
+        }
+
+        match decision.map_or(self.default_allow, |(_, allowed)| allowed) {
+            true => Ok(()),
+            false => Err(AccessError::Denied(permission, path.display().to_string())),
+        }
+    }
+}
+
+pub fn check_read_only(read_only: bool, permission: Permission) -> Result<(), AccessError> {
+    match read_only && permission.modifies() {
+        true => Err(AccessError::ReadOnly),
+        false => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> AccessPolicy {
+        let mut policy = AccessPolicy::new(true);
+        policy.add_rule(vec![String::from("*")], "shared", vec![Permission::Read], vec![Permission::Write]);
+        policy.add_rule(vec![String::from("alice")], "shared/drop", vec![Permission::Write], vec![]);
+        policy.add_rule(vec![String::from("alice")], "shared/drop/locked", vec![], vec![Permission::Write]);
+        policy
+    }
+
+    #[test]
+    fn longest_prefix_decides() {
+        let policy = policy();
+        assert!(policy.check("alice", Permission::Write, Path::new("shared/file")).is_err());
+        assert!(policy.check("alice", Permission::Write, Path::new("shared/drop/file")).is_ok());
+        assert!(policy.check("alice", Permission::Write, Path::new("shared/drop/locked/file")).is_err());
+        assert!(policy.check("bob", Permission::Write, Path::new("shared/drop/file")).is_err());
+    }
+
+    #[test]
+    fn deny_wins_on_the_same_prefix() {
+        let mut policy = AccessPolicy::new(false);
+        policy.add_rule(vec![String::from("*")], "docs", vec![Permission::Delete], vec![]);
+        policy.add_rule(vec![String::from("bob")], "docs", vec![], vec![Permission::Delete]);
+
+        assert!(policy.check("alice", Permission::Delete, Path::new("docs/a")).is_ok());
+        assert!(policy.check("bob", Permission::Delete, Path::new("docs/a")).is_err());
+    }
+
+    #[test]
+    fn unmatched_paths_fall_back_to_the_default() {
+        let policy = policy();
+        assert!(policy.check("bob", Permission::Delete, Path::new("shared/file")).is_ok());
+        assert!(policy.check("bob", Permission::Write, Path::new("other/file")).is_ok());
+        assert!(AccessPolicy::new(false).check("bob", Permission::Read, Path::new("file")).is_err());
+    }
+
+    #[test]
+    fn prefixes_match_whole_components() {
+        let policy = policy();
+        assert!(policy.check("bob", Permission::Write, Path::new("shared_other/file")).is_ok());
+        assert!(policy.check("bob", Permission::Write, Path::new("other/../shared/file")).is_err());
+    }
+
+    #[test]
+    fn roots_and_restrictions() {
+        let mut policy = AccessPolicy::new(true);
+        assert!(policy.is_unrestricted("alice"));
+
+        policy.set_home(String::from("home/{user}"));
+        policy.set_root(String::from("admin"), String::new());
+        assert_eq!(policy.get_root("alice").as_deref(), Some("home/alice"));
+        assert_eq!(policy.get_root("admin").as_deref(), Some(""));
+        assert!(!policy.is_unrestricted("alice"));
+        assert!(check_read_only(true, Permission::Write).is_err());
+        assert!(check_read_only(true, Permission::List).is_ok());
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs;
+use std::io::Error;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use ed25519_dalek::{Signature, VerifyingKey};
+use ipnet::IpNet;
+use super::users::{Identity, Restrictions};
+
+const KEY_TYPE: &str = "ssh-ed25519";
+// Prepended to the handshake transcript, so a client signature can't be reused for anything else
+const CLIENT_AUTH_CONTEXT: &[u8] = b"fileserver client auth";
+
+#[derive(Debug)]
+pub enum AuthorizedKeysError {
+    ReadFailed(String),
+    InvalidLine(usize),
+    UnsupportedKeyType(usize),
+    InvalidKey(usize),
+    InvalidOption(usize, String),
+    UnknownKey,
+    BadSignature,
+    SourceNotAllowed(IpAddr),
+}
+
+impl From<AuthorizedKeysError> for Error {
+    fn from(error: AuthorizedKeysError) -> Error {
+        match error {
+            AuthorizedKeysError::ReadFailed(path) => Error::other(format!("Authorized keys file {} read failed", path)),
+            AuthorizedKeysError::InvalidLine(line) => {
+                Error::other(format!("Authorized keys line {} should be [options] ssh-ed25519 key user", line))
+            }
+            AuthorizedKeysError::UnsupportedKeyType(line) => {
+                Error::other(format!("Authorized keys line {} is not an ssh-ed25519 key", line))
+            }
+            AuthorizedKeysError::InvalidKey(line) => {
+                Error::other(format!("Authorized keys line {} has an invalid key", line))
+            }
+            AuthorizedKeysError::InvalidOption(line, option) => {
+                Error::other(format!("Authorized keys line {} has an invalid option {}", line, option))
+            }
+            AuthorizedKeysError::UnknownKey => Error::other("Client key is not authorized"),
+            AuthorizedKeysError::BadSignature => Error::other("Client key signature is invalid"),
+            AuthorizedKeysError::SourceNotAllowed(ip) => Error::other(format!("Client key is not allowed from {}", ip)),
+        }
+    }
+}
+
+pub fn client_auth_message(transcript: &[u8]) -> Vec<u8> {
+    [CLIENT_AUTH_CONTEXT, transcript].concat()
+}
+
+// The base64 part of an OpenSSH public key: length prefixed key type, then the length prefixed 32 byte key
+fn decode_ssh_key(key_str: &str) -> Option<VerifyingKey> {
+    let blob = STANDARD.decode(key_str).ok()?;
+    let (key_type, rest) = read_ssh_string(&blob)?;
+    if key_type != KEY_TYPE.as_bytes() {
+        return None;
+    }
+
+    let (key, rest) = read_ssh_string(rest)?;
+    if !rest.is_empty() {
+        return None;
+    }
+    VerifyingKey::from_bytes(&key.try_into().ok()?).ok()
+}
+
+fn read_ssh_string(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let length = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let end = 4usize.checked_add(length)?;
+    Some((data.get(4..end)?, &data[end..]))
+}
+
+// Comma separated: read-only, path=<prefix> and from=<cidr>, from may be repeated
+fn parse_options(options_str: &str, line: usize) -> Result<Restrictions, AuthorizedKeysError> {
+    let mut restrictions = Restrictions::default();
+    for option in options_str.split(',') {
+        match option.split_once('=') {
+            None if option == "read-only" => restrictions.read_only = true,
+            Some(("path", prefix)) if !prefix.is_empty() => restrictions.path_prefix = Some(prefix.to_string()),
+            Some(("from", cidr)) => {
+                let net = IpNet::from_str(cidr).or_else(|_| IpAddr::from_str(cidr).map(IpNet::from))
+                    .map_err(|_| AuthorizedKeysError::InvalidOption(line, option.to_string()))?;
+                restrictions.sources.push(net);
+            }
+            _ => return Err(AuthorizedKeysError::InvalidOption(line, option.to_string())),
+        }
+    }
+
+    Ok(restrictions)
+}
+
+#[derive(Clone)]
+struct AuthorizedKey {
+    key: VerifyingKey,
+    user: String,
+    restrictions: Restrictions,
+}
+
+#[derive(Clone)]
+pub struct AuthorizedKeys {
+    keys: Vec<AuthorizedKey>,
+}
+
+impl AuthorizedKeys {
+    // One [options] ssh-ed25519 <base64> <user> per line, blank lines and lines starting with # are skipped
+    pub fn load(path: &Path) -> Result<Self, AuthorizedKeysError> {
+        let keys_str = fs::read_to_string(path)
+            .map_err(|_| AuthorizedKeysError::ReadFailed(path.display().to_string()))?;
+        let mut keys = Vec::new();
+        for (index, line) in keys_str.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let (restrictions, tokens) = match tokens.first() {
+                Some(&KEY_TYPE) => (Restrictions::default(), &tokens[..]),
+                Some(options) => (parse_options(options, index + 1)?, &tokens[1..]),
+                None => return Err(AuthorizedKeysError::InvalidLine(index + 1)),
+            };
+            let (key_type, key_str, user) = match tokens {
+                [key_type, key_str, user] => (*key_type, *key_str, *user),
+                _ => return Err(AuthorizedKeysError::InvalidLine(index + 1)),
+            };
+            if key_type != KEY_TYPE {
+                return Err(AuthorizedKeysError::UnsupportedKeyType(index + 1));
+            }
+
+            let key = decode_ssh_key(key_str).ok_or(AuthorizedKeysError::InvalidKey(index + 1))?;
+            keys.push(AuthorizedKey { key, user: user.to_string(), restrictions });
+        }
+
+        Ok(AuthorizedKeys { keys })
+    }
+
+    pub fn verify(&self, public_key: &[u8], signature: &[u8], message: &[u8], source: IpAddr)
+        -> Result<Identity, AuthorizedKeysError> {
+        let authorized = self.keys.iter().find(|authorized| authorized.key.as_bytes()[..] == *public_key)
+            .ok_or(AuthorizedKeysError::UnknownKey)?;
+        let signature = Signature::from_slice(signature).map_err(|_| AuthorizedKeysError::BadSignature)?;
+        authorized.key.verify_strict(message, &signature).map_err(|_| AuthorizedKeysError::BadSignature)?;
+        if !authorized.restrictions.allows_source(source) {
+            return Err(AuthorizedKeysError::SourceNotAllowed(source));
+        }
+
+        Ok(Identity::new(authorized.user.clone(), authorized.restrictions.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::{Signer, SigningKey};
+    use crate::test_utils::temp_dir;
+    use super::*;
+
+    const TRANSCRIPT: [u8; 32] = [9; 32];
+
+    fn ssh_key(signing_key: &SigningKey) -> String {
+        let mut blob = Vec::new();
+        for part in [KEY_TYPE.as_bytes(), signing_key.verifying_key().as_bytes()] {
+            blob.extend_from_slice(&(part.len() as u32).to_be_bytes());
+            blob.extend_from_slice(part);
+        }
+        STANDARD.encode(blob)
+    }
+
+    fn load(name: &str, keys_str: &str) -> Result<AuthorizedKeys, AuthorizedKeysError> {
+        let dir = temp_dir(name);
+        let path = dir.join("authorized_keys");
+        fs::write(&path, keys_str).unwrap();
+        let keys = AuthorizedKeys::load(&path);
+        fs::remove_dir_all(dir).unwrap();
+        keys
+    }
+
+    fn source(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn options_become_restrictions() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let keys_str = format!("read-only,path=shared/drop,from=10.0.0.0/8,from=::1 {} {} alice\n",
+                               KEY_TYPE, ssh_key(&key));
+        let keys = load("authorized-keys-options", &keys_str).unwrap();
+        let message = client_auth_message(&TRANSCRIPT);
+        let signature = key.sign(&message).to_bytes();
+
+        let identity = keys.verify(&public_key, &signature, &message, source("10.1.2.3")).unwrap();
+        assert_eq!(identity.get_name(), "alice");
+        assert!(identity.get_restrictions().read_only);
+        assert_eq!(identity.get_restrictions().path_prefix.as_deref(), Some("shared/drop"));
+        assert!(keys.verify(&public_key, &signature, &message, source("::1")).is_ok());
+        assert!(matches!(keys.verify(&public_key, &signature, &message, source("192.168.1.1")),
+                         Err(AuthorizedKeysError::SourceNotAllowed(_))));
+    }
+
+    #[test]
+    fn keys_without_options_are_unrestricted() {
+        let key = SigningKey::from_bytes(&[2; 32]);
+        let keys = load("authorized-keys-plain", &format!("# clients\n\n{} {} bob\n", KEY_TYPE, ssh_key(&key)))
+            .unwrap();
+        let message = client_auth_message(&TRANSCRIPT);
+        let signature = key.sign(&message).to_bytes();
+
+        let identity = keys.verify(key.verifying_key().as_bytes(), &signature, &message, source("192.168.1.1"))
+            .unwrap();
+        assert_eq!(identity.get_name(), "bob");
+        assert!(!identity.get_restrictions().read_only);
+        assert!(identity.get_restrictions().path_prefix.is_none());
+    }
+
+    #[test]
+    fn signatures_have_to_cover_the_transcript() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let keys = load("authorized-keys-signatures", &format!("{} {} alice\n", KEY_TYPE, ssh_key(&key))).unwrap();
+        let message = client_auth_message(&TRANSCRIPT);
+        let other_message = client_auth_message(&[8; 32]);
+        let localhost = source("127.0.0.1");
+
+        let signature = key.sign(&message).to_bytes();
+        assert!(keys.verify(&public_key, &signature, &message, localhost).is_ok());
+        assert!(matches!(keys.verify(&public_key, &signature, &other_message, localhost),
+                         Err(AuthorizedKeysError::BadSignature)));
+        let bare_signature = key.sign(&TRANSCRIPT).to_bytes();
+        assert!(matches!(keys.verify(&public_key, &bare_signature, &message, localhost),
+                         Err(AuthorizedKeysError::BadSignature)));
+        assert!(matches!(keys.verify(&public_key, &signature[..32], &message, localhost),
+                         Err(AuthorizedKeysError::BadSignature)));
+
+        let stranger = SigningKey::from_bytes(&[3; 32]);
+        let stranger_signature = stranger.sign(&message).to_bytes();
+        assert!(matches!(keys.verify(stranger.verifying_key().as_bytes(), &stranger_signature, &message, localhost),
+                         Err(AuthorizedKeysError::UnknownKey)));
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        let key_str = ssh_key(&SigningKey::from_bytes(&[1; 32]));
+
+        assert!(matches!(load("authorized-keys-short", &format!("{} {}\n", KEY_TYPE, key_str)),
+                         Err(AuthorizedKeysError::InvalidLine(1))));
+        assert!(matches!(load("authorized-keys-type", &format!("read-only ssh-rsa {} alice\n", key_str)),
+                         Err(AuthorizedKeysError::UnsupportedKeyType(1))));
+        assert!(matches!(load("authorized-keys-key", &format!("{} bm90IGEga2V5 alice\n", KEY_TYPE)),
+                         Err(AuthorizedKeysError::InvalidKey(1))));
+        assert!(matches!(load("authorized-keys-from", &format!("\nfrom=nowhere {} {} alice\n", KEY_TYPE, key_str)),
+                         Err(AuthorizedKeysError::InvalidOption(2, _))));
+        assert!(matches!(load("authorized-keys-path", &format!("path= {} {} alice\n", KEY_TYPE, key_str)),
+                         Err(AuthorizedKeysError::InvalidOption(1, _))));
+    }
+}
@@ -0,0 +1,68 @@
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use super::cypher::{CypherError, NONCE_SIZE};
+
+pub const CIPHER_KEY_SIZE: usize = 32;
+const AES_256_GCM: &str = "aes-256-gcm";
+const CHACHA20_POLY1305: &str = "chacha20-poly1305";
+
+// An AEAD with a 256 bit key and a 96 bit nonce
+pub trait Cipher: Send {
+    fn encrypt(&self, nonce: &[u8; NONCE_SIZE], payload: Payload) -> Result<Vec<u8>, CypherError>;
+    fn decrypt(&self, nonce: &[u8; NONCE_SIZE], payload: Payload) -> Result<Vec<u8>, CypherError>;
+}
+
+impl Cipher for Aes256Gcm {
+    fn encrypt(&self, nonce: &[u8; NONCE_SIZE], payload: Payload) -> Result<Vec<u8>, CypherError> {
+        Aead::encrypt(self, nonce.into(), payload).map_err(|_| CypherError::EncryptError)
+    }
+
+    fn decrypt(&self, nonce: &[u8; NONCE_SIZE], payload: Payload) -> Result<Vec<u8>, CypherError> {
+        Aead::decrypt(self, nonce.into(), payload).map_err(|_| CypherError::DecryptionError)
+    }
+}
+
+impl Cipher for ChaCha20Poly1305 {
+    fn encrypt(&self, nonce: &[u8; NONCE_SIZE], payload: Payload) -> Result<Vec<u8>, CypherError> {
+        Aead::encrypt(self, nonce.into(), payload).map_err(|_| CypherError::EncryptError)
+    }
+
+    fn decrypt(&self, nonce: &[u8; NONCE_SIZE], payload: Payload) -> Result<Vec<u8>, CypherError> {
+        Aead::decrypt(self, nonce.into(), payload).map_err(|_| CypherError::DecryptionError)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CipherSuite {
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            CipherSuite::Aes256Gcm => AES_256_GCM,
+            CipherSuite::ChaCha20Poly1305 => CHACHA20_POLY1305,
+        }
+    }
+
+    pub fn new_cipher(&self, key: &[u8; CIPHER_KEY_SIZE]) -> Box<dyn Cipher> {
+        match self {
+            CipherSuite::Aes256Gcm => Box::new(Aes256Gcm::new(key.into())),
+            CipherSuite::ChaCha20Poly1305 => Box::new(ChaCha20Poly1305::new(key.into())),
+        }
+    }
+}
+
+impl TryFrom<&str> for CipherSuite {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            AES_256_GCM => Ok(CipherSuite::Aes256Gcm),
+            CHACHA20_POLY1305 => Ok(CipherSuite::ChaCha20Poly1305),
+            _ => Err(()),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
+use super::config::{Config, KeyConfig};
+
+#[derive(Parser)]
+#[command(name = "fileserver", version, about = "Encrypted UDP file server", args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Running without a subcommand is the same as serve
+    #[command(flatten)]
+    serve: ServeArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Start serving files
+    Serve(ServeArgs),
+    /// Load and validate the configuration without writing anything, then exit
+    Check(ServeArgs),
+    /// Read a password from stdin and print its hash for the users file
+    HashPassword,
+}
+
+// Flags win over environment variables, which win over the config file
+#[derive(Args, Clone)]
+pub struct ServeArgs {
+    /// Path to the TOML config file
+    #[arg(long, env = "FILESERVER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on, may be repeated or comma separated
+    #[arg(long, env = "FILESERVER_BIND", value_delimiter = ',')]
+    pub bind: Vec<String>,
+
+    /// Directory every client path resolves under
+    #[arg(long, env = "FILESERVER_ROOT")]
+    pub root: Option<String>,
+
+    /// Symlink policy: deny, within-root or follow
+    #[arg(long, env = "FILESERVER_SYMLINKS")]
+    pub symlinks: Option<String>,
+
+    /// File holding a 32 byte pre-shared key in hex or base64, used as key 0 instead of the configured keys
+    #[arg(long, env = "FILESERVER_KEY_FILE")]
+    pub key_file: Option<String>,
+
+    /// Ed25519 host key the server signs handshakes with, generated when missing
+    #[arg(long, env = "FILESERVER_HOST_KEY_FILE")]
+    pub host_key_file: Option<String>,
+
+    /// File with one name:argon2-hash line per user, clients must authenticate once it is set
+    #[arg(long, env = "FILESERVER_USERS_FILE")]
+    pub users_file: Option<String>,
+
+    /// File with one [options] ssh-ed25519 key user line per client key
+    #[arg(long, env = "FILESERVER_AUTHORIZED_KEYS_FILE")]
+    pub authorized_keys_file: Option<String>,
+
+    /// Policy file with roles, groups and their assignments, reloaded when it changes
+    #[arg(long, env = "FILESERVER_POLICY_FILE")]
+    pub policy_file: Option<String>,
+
+    /// Log level: off, error, warn, info, debug or trace
+    #[arg(long, env = "FILESERVER_LOG_LEVEL")]
+    pub log_level: Option<String>,
+}
+
+impl Cli {
+    pub fn into_command(self) -> Command {
+        self.command.unwrap_or(Command::Serve(self.serve))
+    }
+}
+
+impl ServeArgs {
+    pub fn apply(&self, config: &mut Config) {
+        if !self.bind.is_empty() { config.server.bind = self.bind.clone(); }
+        if let Some(root) = &self.root { config.storage.root = root.clone(); }
+        if let Some(symlinks) = &self.symlinks { config.storage.symlinks = symlinks.clone(); }
+        if let Some(key_file) = &self.key_file {
+            config.security.keys = vec![KeyConfig { id: 0, file: Some(key_file.clone()), env: None, retired_at: None }];
+        }
+        if let Some(host_key_file) = &self.host_key_file { config.security.host_key_file = host_key_file.clone(); }
+        if let Some(users_file) = &self.users_file { config.security.users_file = Some(users_file.clone()); }
+        if let Some(authorized_keys_file) = &self.authorized_keys_file {
+            config.security.authorized_keys_file = Some(authorized_keys_file.clone());
+        }
+        if let Some(policy_file) = &self.policy_file { config.access.policy_file = Some(policy_file.clone()); }
+        if let Some(log_level) = &self.log_level { config.log.level = log_level.clone(); }
+    }
+}
@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use log::{LevelFilter, warn};
+use serde::Deserialize;
+use protocol::limits::Limits;
+use super::keys::{read_key_env, read_key_file, KeyRing};
+use super::cipher::CipherSuite;
+use super::cypher::RekeyLimits;
+use super::reliability::Timeouts;
+use super::sandbox::SymlinkPolicy;
+use super::users::Users;
+use super::authorized_keys::AuthorizedKeys;
+use super::host_key::HostKey;
+use protocol::permissions::Permission;
+use super::access::AccessPolicy;
+use super::roles::Roles;
+use super::quotas::{QuotaLimits, Quotas};
+use super::filesystem::WriteOptions;
+
+const DEFAULT_BIND: &str = "0.0.0.0:1998";
+const DEFAULT_SESSION_PORT_START: u16 = 40000;
+const DEFAULT_SESSION_PORT_END: u16 = 40999;
+const DEFAULT_ROOT: &str = "storage";
+const DEFAULT_SYMLINKS: &str = "within-root";
+const DEFAULT_RESERVE_BYTES: u64 = 100 * 1024 * 1024;
+const DEFAULT_PARTIAL_EXPIRY_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_RETIRED_KEY_GRACE_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_HOST_KEY_FILE: &str = "fileserver_host_key";
+const DEFAULT_CIPHER_SUITES: [&str; 2] = ["aes-256-gcm", "chacha20-poly1305"];
+const DEFAULT_ACCESS: &str = "allow";
+const DEFAULT_POLICY_RELOAD_SECS: u64 = 5;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    ReadFailed,
+    ParseFailed(String),
+    InvalidBindAddress(String),
+    InvalidSessionPorts,
+    InvalidSymlinkPolicy(String),
+    InvalidLogLevel(String),
+    NoKeys,
+    KeySourceMissing(u8),
+    KeySourceAmbiguous(u8),
+    InvalidCipherSuite(String),
+    NoCipherSuites,
+    InvalidAccessDefault(String),
+    InvalidPermission(String),
+    InvalidQuotaDirectory(String),
+    UserQuotaWithoutRoot(String),
+    ZeroTimeout(String),
+    InvalidRtoRange,
+}
+
+impl From<ConfigError> for Error {
+    fn from(error: ConfigError) -> Error {
+        match error {
+            ConfigError::ReadFailed => Error::other("Config file read failed"),
+            ConfigError::ParseFailed(cause) => Error::other(format!("Config parse failed: {}", cause)),
+            ConfigError::InvalidBindAddress(addr) => Error::other(format!("Invalid bind address: {}", addr)),
+            ConfigError::InvalidSessionPorts => Error::other("Session port range is empty"),
+            ConfigError::InvalidSymlinkPolicy(policy) => {
+                Error::other(format!("Invalid symlink policy {}, should be deny, within-root or follow", policy))
+            }
+            ConfigError::InvalidLogLevel(level) => Error::other(format!("Invalid log level: {}", level)),
+            ConfigError::NoKeys => Error::other("No pre-shared key configured, add [[security.keys]] or --key-file"),
+            ConfigError::KeySourceMissing(id) => Error::other(format!("Key {} needs a file or env", id)),
+            ConfigError::KeySourceAmbiguous(id) => Error::other(format!("Key {} has both a file and env", id)),
+            ConfigError::InvalidCipherSuite(suite) => {
+                Error::other(format!("Invalid cipher suite {}, should be aes-256-gcm or chacha20-poly1305", suite))
+            }
+            ConfigError::NoCipherSuites => Error::other("At least one cipher suite is needed"),
+            ConfigError::InvalidAccessDefault(default) => {
+                Error::other(format!("Invalid access default {}, should be allow or deny", default))
+            }
+            ConfigError::InvalidQuotaDirectory(directory) => {
+                Error::other(format!("Invalid quota directory {}, should be a top-level directory name", directory))
+            }
+            ConfigError::UserQuotaWithoutRoot(user) => {
+                Error::other(format!("Quota of {} needs a root of its own, set home or a non-empty root in [access]",
+                                     user))
+            }
+            ConfigError::InvalidPermission(permission) => {
+                Error::other(format!("Invalid permission {}, should be read, write, delete, list or admin", permission))
+            }
+            ConfigError::ZeroTimeout(name) => Error::other(format!("Timeout {} should be greater than zero", name)),
+            ConfigError::InvalidRtoRange => Error::other("min_rto_ms should not be greater than max_rto_ms"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: Vec<String>,
+    pub session_port_start: u16,
+    pub session_port_end: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { bind: vec![String::from(DEFAULT_BIND)], session_port_start: DEFAULT_SESSION_PORT_START,
+            session_port_end: DEFAULT_SESSION_PORT_END }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub root: String,
+    pub symlinks: String,
+    pub reserve_bytes: u64,
+    pub preallocate: bool,
+    pub partial_expiry_secs: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig { root: String::from(DEFAULT_ROOT), symlinks: String::from(DEFAULT_SYMLINKS),
+            reserve_bytes: DEFAULT_RESERVE_BYTES, preallocate: false,
+            partial_expiry_secs: DEFAULT_PARTIAL_EXPIRY_SECS }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyConfig {
+    pub id: u8,
+    pub file: Option<String>,
+    pub env: Option<String>,
+    // Unix time, clients may keep using the key for the grace period after it
+    pub retired_at: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityConfig {
+    pub keys: Vec<KeyConfig>,
+    pub retired_key_grace_secs: u64,
+    pub host_key_file: String,
+    pub users_file: Option<String>,
+    pub authorized_keys_file: Option<String>,
+    // Most preferred first
+    pub cipher_suites: Vec<String>,
+    pub rekey_after_bytes: Option<u64>,
+    pub rekey_after_packets: Option<u64>,
+}
+
+impl Default for SecurityConfig {
+    fn default() -> Self {
+        SecurityConfig { keys: Vec::new(), retired_key_grace_secs: DEFAULT_RETIRED_KEY_GRACE_SECS,
+            host_key_file: String::from(DEFAULT_HOST_KEY_FILE), users_file: None,
+            authorized_keys_file: None,
+            cipher_suites: DEFAULT_CIPHER_SUITES.iter().map(|suite| suite.to_string()).collect(),
+            rekey_after_bytes: None, rekey_after_packets: None }
+    }
+}
+
+// Users are matched by name, "*" matches everyone including anonymous sessions
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessRuleConfig {
+    pub users: Vec<String>,
+    pub path: String,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    pub default: String,
+    // Root of every authenticated user without an entry in roots, {user} is replaced by the name
+    pub home: Option<String>,
+    pub roots: HashMap<String, String>,
+    pub rules: Vec<AccessRuleConfig>,
+    pub policy_file: Option<String>,
+    pub policy_reload_secs: u64,
+}
+
+impl Default for AccessConfig {
+    fn default() -> Self {
+        AccessConfig { default: String::from(DEFAULT_ACCESS), home: None, roots: HashMap::new(), rules: Vec::new(),
+            policy_file: None, policy_reload_secs: DEFAULT_POLICY_RELOAD_SECS }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotaConfig {
+    pub max_bytes: Option<u64>,
+    pub max_files: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuotasConfig {
+    // For every user without an entry in users
+    pub user: QuotaConfig,
+    pub users: HashMap<String, QuotaConfig>,
+    pub directories: HashMap<String, QuotaConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_path_length: Option<usize>,
+    pub max_path_depth: Option<usize>,
+    pub max_upload_size: Option<u64>,
+    pub max_err_msg_length: Option<usize>,
+    pub max_fields: Option<u8>,
+    pub max_field_length: Option<u16>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub initial_rto_ms: Option<u64>,
+    pub min_rto_ms: Option<u64>,
+    pub max_rto_ms: Option<u64>,
+    pub max_retransmissions: Option<u32>,
+    pub session_idle_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig { level: String::from(DEFAULT_LOG_LEVEL) }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub security: SecurityConfig,
+    pub access: AccessConfig,
+    pub quotas: QuotasConfig,
+    pub limits: LimitsConfig,
+    pub timeouts: TimeoutsConfig,
+    pub log: LogConfig,
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>) -> Result<Self, ConfigError> {
+        let path = match path {
+            Some(path) => path,
+            None => return Ok(Config::default()),
+        };
+
+        let config_str = fs::read_to_string(path).map_err(|_| ConfigError::ReadFailed)?;
+        toml::from_str(&config_str).map_err(|error| ConfigError::ParseFailed(error.message().to_string()))
+    }
+
+    pub fn get_bind_addrs(&self) -> Result<Vec<SocketAddr>, ConfigError> {
+        self.server.bind.iter()
+            .map(|addr| SocketAddr::from_str(addr).map_err(|_| ConfigError::InvalidBindAddress(addr.clone())))
+            .collect()
+    }
+
+    pub fn get_session_ports(&self) -> Result<RangeInclusive<u16>, ConfigError> {
+        if self.server.session_port_start > self.server.session_port_end {
+            return Err(ConfigError::InvalidSessionPorts);
+        }
+
+        Ok(self.server.session_port_start..=self.server.session_port_end)
+    }
+
+    pub fn get_symlink_policy(&self) -> Result<SymlinkPolicy, ConfigError> {
+        SymlinkPolicy::try_from(self.storage.symlinks.as_str())
+            .map_err(|_| ConfigError::InvalidSymlinkPolicy(self.storage.symlinks.clone()))
+    }
+
+    pub fn get_write_options(&self) -> WriteOptions {
+        WriteOptions::new(self.storage.reserve_bytes, self.storage.preallocate)
+    }
+
+    // 0 keeps interrupted uploads until they are resumed or cancelled
+    pub fn get_partial_expiry(&self) -> Option<Duration> {
+        match self.storage.partial_expiry_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    pub fn get_log_level(&self) -> Result<LevelFilter, ConfigError> {
+        LevelFilter::from_str(&self.log.level).map_err(|_| ConfigError::InvalidLogLevel(self.log.level.clone()))
+    }
+
+    pub fn load_keys(&self) -> Result<KeyRing, Error> {
+        if self.security.keys.is_empty() {
+            return Err(ConfigError::NoKeys.into());
+        }
+
+        let mut keys = KeyRing::new(Duration::from_secs(self.security.retired_key_grace_secs));
+        for key_config in &self.security.keys {
+            let key = match (&key_config.file, &key_config.env) {
+                (Some(file), None) => read_key_file(Path::new(file))?,
+                (None, Some(env)) => read_key_env(env)?,
+                (None, None) => return Err(ConfigError::KeySourceMissing(key_config.id).into()),
+                (Some(_), Some(_)) => return Err(ConfigError::KeySourceAmbiguous(key_config.id).into()),
+            };
+
+            let retired_at = key_config.retired_at.map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+            if let Some(retired_at) = retired_at && retired_at <= SystemTime::now() {
+                warn!("Key {} is retired, it is accepted until the grace period ends", key_config.id);
+            }
+            keys.add(key_config.id, key, retired_at)?;
+        }
+
+        Ok(keys)
+    }
+
+    pub fn get_limits(&self) -> Limits {
+        let mut limits = Limits::new();
+        if let Some(max_path_length) = self.limits.max_path_length { limits.set_max_path_length(max_path_length); }
+        if let Some(max_path_depth) = self.limits.max_path_depth { limits.set_max_path_depth(max_path_depth); }
+        if let Some(max_upload_size) = self.limits.max_upload_size { limits.set_max_upload_size(max_upload_size); }
+        if let Some(max_err_msg_length) = self.limits.max_err_msg_length {
+            limits.set_max_err_msg_length(max_err_msg_length);
+        }
+        if let Some(max_fields) = self.limits.max_fields { limits.set_max_fields(max_fields); }
+        if let Some(max_field_length) = self.limits.max_field_length { limits.set_max_field_length(max_field_length); }
+        limits
+    }
+
+    pub fn load_host_key(&self) -> Result<HostKey, Error> {
+        Ok(HostKey::load_or_generate(Path::new(&self.security.host_key_file))?)
+    }
+
+    pub fn read_host_key(&self) -> Result<Option<HostKey>, Error> {
+        Ok(HostKey::load(Path::new(&self.security.host_key_file))?)
+    }
+
+    pub fn load_users(&self) -> Result<Option<Users>, Error> {
+        match &self.security.users_file {
+            Some(users_file) => Ok(Some(Users::load(Path::new(users_file))?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn load_authorized_keys(&self) -> Result<Option<AuthorizedKeys>, Error> {
+        match &self.security.authorized_keys_file {
+            Some(authorized_keys_file) => Ok(Some(AuthorizedKeys::load(Path::new(authorized_keys_file))?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_access_policy(&self) -> Result<AccessPolicy, ConfigError> {
+        let default_allow = match self.access.default.as_str() {
+            "allow" => true,
+            "deny" => false,
+            _ => return Err(ConfigError::InvalidAccessDefault(self.access.default.clone())),
+        };
+
+        let mut policy = AccessPolicy::new(default_allow);
+        if let Some(home) = &self.access.home { policy.set_home(home.clone()); }
+        for (user, root) in &self.access.roots {
+            policy.set_root(user.clone(), root.clone());
+        }
+        for rule in &self.access.rules {
+            policy.add_rule(rule.users.clone(), &rule.path, parse_permissions(&rule.allow)?,
+                            parse_permissions(&rule.deny)?);
+        }
+
+        Ok(policy)
+    }
+
+    pub fn load_roles(&self) -> Result<Option<Roles>, Error> {
+        match &self.access.policy_file {
+            Some(policy_file) => Ok(Some(Roles::load(Path::new(policy_file))?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_policy_reload(&self) -> Duration { Duration::from_secs(self.access.policy_reload_secs.max(1)) }
+
+    pub fn get_quotas(&self, root: &Path) -> Result<Quotas, ConfigError> {
+        let limits = |quota: &QuotaConfig| QuotaLimits::new(quota.max_bytes, quota.max_files);
+        let limited = |quota: &QuotaConfig| quota.max_bytes.is_some() || quota.max_files.is_some();
+        let has_root = |user: &str| match self.access.roots.get(user) {
+            Some(root) => !root.is_empty(),
+            None => self.access.home.is_some(),
+        };
+        if limited(&self.quotas.user) && self.access.home.is_none() {
+            return Err(ConfigError::UserQuotaWithoutRoot(String::from("every user")));
+        }
+
+        let mut quotas = Quotas::new(root, limits(&self.quotas.user));
+        for (user, quota) in &self.quotas.users {
+            if limited(quota) && !has_root(user) {
+                return Err(ConfigError::UserQuotaWithoutRoot(user.clone()));
+            }
+            quotas.set_user(user.clone(), limits(quota));
+        }
+        for (directory, quota) in &self.quotas.directories {
+            if directory.is_empty() || directory.contains(['/', '\\']) || directory == "." || directory == ".." {
+                return Err(ConfigError::InvalidQuotaDirectory(directory.clone()));
+            }
+            quotas.set_directory(directory.clone(), limits(quota));
+        }
+
+        Ok(quotas)
+    }
+
+    pub fn get_cipher_suites(&self) -> Result<Vec<CipherSuite>, ConfigError> {
+        if self.security.cipher_suites.is_empty() {
+            return Err(ConfigError::NoCipherSuites);
+        }
+
+        self.security.cipher_suites.iter()
+            .map(|suite| {
+                CipherSuite::try_from(suite.as_str()).map_err(|_| ConfigError::InvalidCipherSuite(suite.clone()))
+            })
+            .collect()
+    }
+
+    pub fn get_rekey_limits(&self) -> RekeyLimits {
+        let mut rekey = RekeyLimits::new();
+        if let Some(max_bytes) = self.security.rekey_after_bytes { rekey.set_max_bytes(max_bytes); }
+        if let Some(max_packets) = self.security.rekey_after_packets { rekey.set_max_packets(max_packets); }
+        rekey
+    }
+
+    // A zero timeout would retransmit or expire in a busy loop
+    pub fn get_timeouts(&self) -> Result<Timeouts, ConfigError> {
+        let config = &self.timeouts;
+        let durations = [("initial_rto_ms", config.initial_rto_ms), ("min_rto_ms", config.min_rto_ms),
+            ("max_rto_ms", config.max_rto_ms), ("session_idle_secs", config.session_idle_secs)];
+        if let Some((name, _)) = durations.iter().find(|(_, value)| *value == Some(0)) {
+            return Err(ConfigError::ZeroTimeout(name.to_string()));
+        }
+
+        let mut timeouts = Timeouts::new();
+        if let Some(initial_rto_ms) = self.timeouts.initial_rto_ms {
+            timeouts.set_initial_rto(Duration::from_millis(initial_rto_ms));
+        }
+        if let Some(min_rto_ms) = self.timeouts.min_rto_ms { timeouts.set_min_rto(Duration::from_millis(min_rto_ms)); }
+        if let Some(max_rto_ms) = self.timeouts.max_rto_ms { timeouts.set_max_rto(Duration::from_millis(max_rto_ms)); }
+        if let Some(max_retransmissions) = self.timeouts.max_retransmissions {
+            timeouts.set_max_retransmissions(max_retransmissions);
+        }
+        if let Some(session_idle_secs) = self.timeouts.session_idle_secs {
+            timeouts.set_session_idle(Duration::from_secs(session_idle_secs));
+        }
+        if timeouts.get_min_rto() > timeouts.get_max_rto() {
+            return Err(ConfigError::InvalidRtoRange);
+        }
+
+        Ok(timeouts)
+    }
+}
+
+fn parse_permissions(names: &[String]) -> Result<Vec<Permission>, ConfigError> {
+    names.iter()
+        .map(|name| Permission::try_from(name.as_str()).map_err(|_| ConfigError::InvalidPermission(name.clone())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(config_str: &str) -> Config {
+        toml::from_str(config_str).unwrap()
+    }
+
+    #[test]
+    fn timeouts_can_not_be_zero() {
+        assert!(parse("").get_timeouts().is_ok());
+        for name in ["initial_rto_ms", "min_rto_ms", "max_rto_ms", "session_idle_secs"] {
+            let config = parse(&format!("[timeouts]\n{} = 0", name));
+            assert!(matches!(config.get_timeouts(), Err(ConfigError::ZeroTimeout(zero)) if zero == name));
+        }
+    }
+
+    #[test]
+    fn min_rto_can_not_exceed_max_rto() {
+        assert!(parse("[timeouts]\nmin_rto_ms = 100\nmax_rto_ms = 100").get_timeouts().is_ok());
+        let config = parse("[timeouts]\nmin_rto_ms = 500\nmax_rto_ms = 100");
+        assert!(matches!(config.get_timeouts(), Err(ConfigError::InvalidRtoRange)));
+        // Against the default maximum of 10 seconds
+        let config = parse("[timeouts]\nmin_rto_ms = 20000");
+        assert!(matches!(config.get_timeouts(), Err(ConfigError::InvalidRtoRange)));
+    }
+}
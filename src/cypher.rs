@@ -1,42 +1,369 @@
-use std::io::{Error, ErrorKind};
-use aes_gcm::{Aes256Gcm};
-use aes_gcm::aead::{AeadCore, Aead, KeyInit};
+use std::io::Error;
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{Generate, Nonce, Payload};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+use super::cipher::{Cipher, CipherSuite, CIPHER_KEY_SIZE};
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+pub const TRANSCRIPT_SIZE: usize = 32;
+const EPOCH_SIZE: usize = 4;
+const CLIENT_TO_SERVER_INFO: &[u8] = b"fileserver client to server";
+const SERVER_TO_CLIENT_INFO: &[u8] = b"fileserver server to client";
+const REKEY_INFO: &[u8] = b"fileserver rekey";
+const DEFAULT_REKEY_AFTER_BYTES: u64 = 1 << 30;
+const DEFAULT_REKEY_AFTER_PACKETS: u64 = 1 << 24;
+// Clients decide when to rekey, this caps how many times one session may move its receive key on
+const MAX_RECEIVE_EPOCH: u32 = 1 << 16;
 
 #[derive(Debug)]
 pub enum CypherError {
     DecryptionError,
     EncryptError,
     GenerateNonceError,
+    WeakPublicKey,
+    KeyDerivationFailed,
+    UnexpectedEpoch(u32),
+    EpochsExhausted,
+    NoCommonSuite,
 }
 
 impl From<CypherError> for Error {
     fn from(error: CypherError) -> Error {
         match error {
-            CypherError::DecryptionError => Error::new(ErrorKind::Other, "Decryption error"),
-            CypherError::EncryptError => Error::new(ErrorKind::Other, "Encryption error"),
-            CypherError::GenerateNonceError => Error::new(ErrorKind::Other, "Generate nonce error"),
+            CypherError::DecryptionError => Error::other("Decryption error"),
+            CypherError::EncryptError => Error::other("Encryption error"),
+            CypherError::GenerateNonceError => Error::other("Generate nonce error"),
+            CypherError::WeakPublicKey => Error::other("Weak public key"),
+            CypherError::KeyDerivationFailed => Error::other("Key derivation failed"),
+            CypherError::UnexpectedEpoch(epoch) => Error::other(format!("Unexpected key epoch {}", epoch)),
+            CypherError::EpochsExhausted => Error::other("No key epochs left"),
+            CypherError::NoCommonSuite => Error::other("No common cipher suite"),
         }
     }
 }
 
+#[derive(Clone, Copy)]
+pub struct RekeyLimits {
+    max_bytes: u64,
+    max_packets: u64,
+}
+
+impl RekeyLimits {
+    pub fn new() -> Self {
+        RekeyLimits { max_bytes: DEFAULT_REKEY_AFTER_BYTES, max_packets: DEFAULT_REKEY_AFTER_PACKETS }
+    }
+
+    pub fn set_max_bytes(&mut self, max_bytes: u64) { self.max_bytes = max_bytes; }
+    pub fn set_max_packets(&mut self, max_packets: u64) { self.max_packets = max_packets; }
+}
+
+impl Default for RekeyLimits {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone)]
+pub struct CypherOptions {
+    suites: Vec<CipherSuite>,
+    rekey: RekeyLimits,
+}
+
+impl CypherOptions {
+    pub fn new(suites: Vec<CipherSuite>, rekey: RekeyLimits) -> Self {
+        CypherOptions { suites, rekey }
+    }
+}
+
+// The offer is included, so the host signature also vouches that it arrived unchanged
+fn transcript(public_keys: &[u8], offered: &[String], suite: CipherSuite) -> [u8; TRANSCRIPT_SIZE] {
+    let offered = offered.join(",");
+    Sha256::new().chain_update(public_keys).chain_update((offered.len() as u32).to_be_bytes())
+        .chain_update(offered).chain_update(suite.get_name()).finalize().into()
+}
+
+fn expand(secret: &[u8], info: &[u8]) -> Result<[u8; CIPHER_KEY_SIZE], CypherError> {
+    let mut key = [0u8; CIPHER_KEY_SIZE];
+    Hkdf::<Sha256>::from_prk(secret).map_err(|_| CypherError::KeyDerivationFailed)?
+        .expand(info, &mut key).map_err(|_| CypherError::KeyDerivationFailed)?;
+    Ok(key)
+}
+
+// One direction of traffic, its nonces are the key epoch followed by a packet counter
+struct TrafficKey {
+    suite: CipherSuite,
+    secret: [u8; CIPHER_KEY_SIZE],
+    cypher: Box<dyn Cipher>,
+    epoch: u32,
+    packets: u64,
+    bytes: u64,
+}
+
+impl TrafficKey {
+    fn new(suite: CipherSuite, secret: [u8; CIPHER_KEY_SIZE], epoch: u32) -> Self {
+        TrafficKey { suite, secret, cypher: suite.new_cipher(&secret), epoch, packets: 0, bytes: 0 }
+    }
+
+    // The next secret is derived from the current one, so old traffic stays safe if a later key leaks
+    fn next(&self) -> Result<Self, CypherError> {
+        let epoch = self.epoch.checked_add(1).ok_or(CypherError::EpochsExhausted)?;
+        Ok(TrafficKey::new(self.suite, expand(&self.secret, REKEY_INFO)?, epoch))
+    }
+
+    fn next_nonce(&mut self, length: usize) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..EPOCH_SIZE].copy_from_slice(&self.epoch.to_be_bytes());
+        nonce[EPOCH_SIZE..].copy_from_slice(&self.packets.to_be_bytes());
+        self.packets += 1;
+        self.bytes = self.bytes.saturating_add(length as u64);
+        nonce
+    }
+
+    fn exhausted(&self, limits: &RekeyLimits) -> bool {
+        self.packets >= limits.max_packets || self.bytes >= limits.max_bytes
+    }
+}
+
+struct Traffic {
+    send: TrafficKey,
+    receive: TrafficKey,
+    // Kept for datagrams the client sent just before it rekeyed
+    previous_receive: Option<TrafficKey>,
+    // Hash of both public keys, the offered suites and the chosen one, signatures over it are bound to this session
+    transcript: [u8; TRANSCRIPT_SIZE],
+}
+
+// The handshake itself is always AES-256-GCM under the pre-shared key, the negotiated suite applies after it
 pub struct Cypher {
-    cypher: Aes256Gcm,
+    key: [u8; CIPHER_KEY_SIZE],
+    cypher: Box<dyn Cipher>,
+    pending: Option<Traffic>,
+    traffic: Option<Traffic>,
+    options: CypherOptions,
 }
 
 impl Cypher {
-    pub fn new(key: &[u8; 32]) -> Self {
-        let cypher = Aes256Gcm::new(key.into());
-        Self { cypher }
+    pub fn new(key: &[u8; CIPHER_KEY_SIZE], options: CypherOptions) -> Self {
+        let cypher = CipherSuite::Aes256Gcm.new_cipher(key);
+        Self { key: *key, cypher, pending: None, traffic: None, options }
+    }
+
+    // First suite of the server's list the client offered, clients that offer nothing only know AES-256-GCM
+    pub fn negotiate(&self, offered: &[String]) -> Result<CipherSuite, CypherError> {
+        let offered: Vec<&str> = match offered.is_empty() {
+            true => vec![CipherSuite::Aes256Gcm.get_name()],
+            false => offered.iter().map(|name| name.as_str()).collect(),
+        };
+
+        self.options.suites.iter().find(|suite| offered.contains(&suite.get_name())).copied()
+            .ok_or(CypherError::NoCommonSuite)
+    }
+
+    // X25519 exchange, the pre-shared key is mixed into HKDF so only its holders derive the traffic keys
+    pub fn handshake(&mut self, peer_public_key: &[u8; PUBLIC_KEY_SIZE], offered: &[String], suite: CipherSuite)
+        -> Result<[u8; PUBLIC_KEY_SIZE], CypherError> {
+        let secret = EphemeralSecret::random();
+        let public_key = PublicKey::from(&secret);
+        let shared_secret = secret.diffie_hellman(&PublicKey::from(*peer_public_key));
+        if !shared_secret.was_contributory() {
+            return Err(CypherError::WeakPublicKey);
+        }
+
+        let salt = [&peer_public_key[..], public_key.as_bytes()].concat();
+        let input_key = [shared_secret.as_bytes(), &self.key[..]].concat();
+        let (session_secret, _) = Hkdf::<Sha256>::extract(Some(&salt), &input_key);
+        let suite_name = suite.get_name().as_bytes();
+        let send_secret = expand(&session_secret, &[SERVER_TO_CLIENT_INFO, suite_name].concat())?;
+        let receive_secret = expand(&session_secret, &[CLIENT_TO_SERVER_INFO, suite_name].concat())?;
+        let send = TrafficKey::new(suite, send_secret, 0);
+        let receive = TrafficKey::new(suite, receive_secret, 0);
+        let transcript = transcript(&salt, offered, suite);
+
+        self.pending = Some(Traffic { send, receive, previous_receive: None, transcript });
+        Ok(*public_key.as_bytes())
+    }
+
+    // Called once the handshake response went out under the pre-shared key
+    pub fn commit(&mut self) {
+        if let Some(traffic) = self.pending.take() {
+            self.traffic = Some(traffic);
+        }
+    }
+
+    pub fn get_transcript(&self) -> Option<&[u8; TRANSCRIPT_SIZE]> {
+        self.pending.as_ref().or(self.traffic.as_ref()).map(|traffic| &traffic.transcript)
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, CypherError> {
-        let nonce = Aes256Gcm::generate_nonce().map_err(|_| CypherError::GenerateNonceError)?;
-        let encrypted_data = self.cypher.encrypt(&nonce, data).map_err(|_| CypherError::EncryptError)?;
+    pub fn encrypt(&mut self, data: &[u8], associated_data: &[u8]) -> Result<Vec<u8>, CypherError> {
+        let payload = Payload { msg: data, aad: associated_data };
+        let traffic = match &mut self.traffic {
+            Some(traffic) => traffic,
+            // The pre-shared key is used by every session, so only random nonces are safe with it
+            None => {
+                let nonce = Nonce::<Aes256Gcm>::try_generate().map_err(|_| CypherError::GenerateNonceError)?;
+                let nonce = <[u8; NONCE_SIZE]>::from(nonce);
+                let encrypted_data = self.cypher.encrypt(&nonce, payload)?;
+                return Ok([nonce.to_vec(), encrypted_data].concat());
+            }
+        };
+
+        if traffic.send.exhausted(&self.options.rekey) {
+            traffic.send = traffic.send.next()?;
+        }
+        let nonce = traffic.send.next_nonce(data.len());
+        let encrypted_data = traffic.send.cypher.encrypt(&nonce, payload)?;
         Ok([nonce.to_vec(), encrypted_data].concat())
     }
 
-    pub fn decrypt(&self, nonce_bytes: &[u8;12], encrypted_data: &[u8]) -> Result<Vec<u8>, CypherError> {
-        let nonce = nonce_bytes.into();
-        Ok(self.cypher.decrypt(nonce, encrypted_data).map_err(|_| CypherError::DecryptionError)?)
+    // A datagram from the next epoch means the client rekeyed, the receive key follows it once it authenticates
+    pub fn decrypt(&mut self, nonce_bytes: &[u8; NONCE_SIZE], encrypted_data: &[u8], associated_data: &[u8])
+        -> Result<Vec<u8>, CypherError> {
+        let payload = Payload { msg: encrypted_data, aad: associated_data };
+        let traffic = match &mut self.traffic {
+            Some(traffic) => traffic,
+            None => return self.cypher.decrypt(nonce_bytes, payload),
+        };
+
+        let epoch = u32::from_be_bytes([nonce_bytes[0], nonce_bytes[1], nonce_bytes[2], nonce_bytes[3]]);
+        if epoch == traffic.receive.epoch {
+            return traffic.receive.cypher.decrypt(nonce_bytes, payload);
+        }
+        if let Some(previous) = &traffic.previous_receive && epoch == previous.epoch {
+            return previous.cypher.decrypt(nonce_bytes, payload);
+        }
+        if Some(epoch) != traffic.receive.epoch.checked_add(1) {
+            return Err(CypherError::UnexpectedEpoch(epoch));
+        }
+        if epoch > MAX_RECEIVE_EPOCH {
+            return Err(CypherError::EpochsExhausted);
+        }
+
+        let next = traffic.receive.next()?;
+        let data = next.cypher.decrypt(nonce_bytes, payload)?;
+        traffic.previous_receive = Some(std::mem::replace(&mut traffic.receive, next));
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; CIPHER_KEY_SIZE] = [7; CIPHER_KEY_SIZE];
+    const ASSOCIATED_DATA: &[u8] = b"session";
+
+    fn traffic(suite: CipherSuite, send: u8, receive: u8) -> Traffic {
+        Traffic { send: TrafficKey::new(suite, [send; CIPHER_KEY_SIZE], 0),
+            receive: TrafficKey::new(suite, [receive; CIPHER_KEY_SIZE], 0), previous_receive: None,
+            transcript: [0; TRANSCRIPT_SIZE] }
+    }
+
+    // Both ends of an established session, each moves to a new send key after max_packets datagrams
+    fn session(max_packets: u64) -> (Cypher, Cypher) {
+        let mut rekey = RekeyLimits::new();
+        rekey.set_max_packets(max_packets);
+        let options = CypherOptions::new(vec![CipherSuite::ChaCha20Poly1305], rekey);
+        let mut server = Cypher::new(&KEY, options.clone());
+        let mut client = Cypher::new(&KEY, options);
+        server.traffic = Some(traffic(CipherSuite::ChaCha20Poly1305, 1, 2));
+        client.traffic = Some(traffic(CipherSuite::ChaCha20Poly1305, 2, 1));
+        (server, client)
+    }
+
+    fn seal(cypher: &mut Cypher, data: &[u8]) -> ([u8; NONCE_SIZE], Vec<u8>) {
+        let datagram = cypher.encrypt(data, ASSOCIATED_DATA).unwrap();
+        let (nonce, encrypted_data) = datagram.split_at(NONCE_SIZE);
+        (nonce.try_into().unwrap(), encrypted_data.to_vec())
+    }
+
+    fn offer(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| String::from(*name)).collect()
+    }
+
+    #[test]
+    fn traffic_round_trips_across_rekeys() {
+        let (mut server, mut client) = session(2);
+        for i in 0..5u8 {
+            let (nonce, encrypted_data) = seal(&mut client, &[i]);
+            assert_eq!(server.decrypt(&nonce, &encrypted_data, ASSOCIATED_DATA).unwrap(), [i]);
+            let (nonce, encrypted_data) = seal(&mut server, &[i]);
+            assert_eq!(client.decrypt(&nonce, &encrypted_data, ASSOCIATED_DATA).unwrap(), [i]);
+        }
+
+        assert_eq!(server.traffic.as_ref().unwrap().receive.epoch, 2);
+        assert_eq!(client.traffic.as_ref().unwrap().receive.epoch, 2);
+    }
+
+    #[test]
+    fn late_datagrams_of_the_previous_epoch_are_accepted() {
+        let (mut server, mut client) = session(1);
+        let (late_nonce, late_data) = seal(&mut client, b"late");
+        let (nonce, encrypted_data) = seal(&mut client, b"next");
+
+        assert_eq!(server.decrypt(&nonce, &encrypted_data, ASSOCIATED_DATA).unwrap(), b"next");
+        assert_eq!(server.decrypt(&late_nonce, &late_data, ASSOCIATED_DATA).unwrap(), b"late");
+    }
+
+    #[test]
+    fn epochs_can_not_be_skipped() {
+        let (mut server, mut client) = session(1);
+        seal(&mut client, b"first");
+        seal(&mut client, b"second");
+        let (nonce, encrypted_data) = seal(&mut client, b"third");
+
+        assert!(matches!(server.decrypt(&nonce, &encrypted_data, ASSOCIATED_DATA),
+                         Err(CypherError::UnexpectedEpoch(2))));
+    }
+
+    #[test]
+    fn receive_epochs_are_capped() {
+        let (mut server, mut client) = session(1);
+        server.traffic.as_mut().unwrap().receive.epoch = MAX_RECEIVE_EPOCH - 1;
+        client.traffic.as_mut().unwrap().send.epoch = MAX_RECEIVE_EPOCH - 1;
+        seal(&mut client, b"current");
+        let (nonce, encrypted_data) = seal(&mut client, b"last");
+        assert!(server.decrypt(&nonce, &encrypted_data, ASSOCIATED_DATA).is_ok());
+
+        let (nonce, encrypted_data) = seal(&mut client, b"past");
+        assert!(matches!(server.decrypt(&nonce, &encrypted_data, ASSOCIATED_DATA),
+                         Err(CypherError::EpochsExhausted)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn negotiation_follows_the_server_preference() {
+        let suites = vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm];
+        let cypher = Cypher::new(&KEY, CypherOptions::new(suites, RekeyLimits::new()));
+
+        assert_eq!(cypher.negotiate(&offer(&["aes-256-gcm", "chacha20-poly1305"])).unwrap(),
+                   CipherSuite::ChaCha20Poly1305);
+        assert_eq!(cypher.negotiate(&offer(&["unknown", "aes-256-gcm"])).unwrap(), CipherSuite::Aes256Gcm);
+    }
+
+    #[test]
+    fn clients_offering_nothing_get_aes_256_gcm() {
+        let suites = vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm];
+        let cypher = Cypher::new(&KEY, CypherOptions::new(suites, RekeyLimits::new()));
+        assert_eq!(cypher.negotiate(&[]).unwrap(), CipherSuite::Aes256Gcm);
+
+        let cypher = Cypher::new(&KEY, CypherOptions::new(vec![CipherSuite::ChaCha20Poly1305], RekeyLimits::new()));
+        assert!(matches!(cypher.negotiate(&[]), Err(CypherError::NoCommonSuite)));
+    }
+
+    #[test]
+    fn transcripts_cover_the_offer() {
+        let public_keys = [1u8; 2 * PUBLIC_KEY_SIZE];
+        let full = transcript(&public_keys, &offer(&["chacha20-poly1305", "aes-256-gcm"]), CipherSuite::Aes256Gcm);
+        let stripped = transcript(&public_keys, &offer(&["aes-256-gcm"]), CipherSuite::Aes256Gcm);
+        assert_ne!(full, stripped);
+        assert_ne!(stripped, transcript(&public_keys, &[], CipherSuite::Aes256Gcm));
+    }
+
+    #[test]
+    fn negotiation_fails_without_a_common_suite() {
+        let cypher = Cypher::new(&KEY, CypherOptions::new(vec![CipherSuite::Aes256Gcm], RekeyLimits::new()));
+        assert!(matches!(cypher.negotiate(&offer(&["chacha20-poly1305"])), Err(CypherError::NoCommonSuite)));
+    }
+}
@@ -0,0 +1,147 @@
+use std::io::Error;
+use aes_gcm::aead::Payload;
+use crc32fast::hash;
+use super::cipher::CipherSuite;
+use super::cypher::NONCE_SIZE;
+use super::keys::KeyRing;
+
+const CRC_SIZE: usize = 4;
+const KEY_ID_SIZE: usize = 1;
+const SESSION_ID_SIZE: usize = 4;
+const SEQUENCE_SIZE: usize = 4;
+pub const HEADER_SIZE: usize = CRC_SIZE + KEY_ID_SIZE + SESSION_ID_SIZE + SEQUENCE_SIZE + NONCE_SIZE;
+pub const CLIENT_TO_SERVER: u8 = 0;
+pub const SERVER_TO_CLIENT: u8 = 1;
+
+#[derive(Debug)]
+pub enum DatagramError {
+    TooShort(usize),
+    CrcMismatch,
+}
+
+impl From<DatagramError> for Error {
+    fn from(error: DatagramError) -> Error {
+        match error {
+            DatagramError::TooShort(size) => Error::other(format!("Datagram too short: {} bytes", size)),
+            DatagramError::CrcMismatch => Error::other("CRC mismatch"),
+        }
+    }
+}
+
+// Layout: crc | key id | session id | sequence | nonce | encrypted data, the crc covers everything after itself.
+// The session id is 0 until the client learns its id from the handshake
+pub struct Header {
+    key_id: u8,
+    session_id: u32,
+    sequence: u32,
+    nonce: [u8; NONCE_SIZE],
+}
+
+impl Header {
+    pub fn parse(datagram: &[u8]) -> Result<Header, DatagramError> {
+        if datagram.len() < HEADER_SIZE {
+            return Err(DatagramError::TooShort(datagram.len()));
+        }
+
+        let crc = u32::from_be_bytes([datagram[0], datagram[1], datagram[2], datagram[3]]);
+        if crc != hash(&datagram[CRC_SIZE..]) {
+            return Err(DatagramError::CrcMismatch);
+        }
+
+        let session_id_start = CRC_SIZE + KEY_ID_SIZE;
+        let sequence_start = session_id_start + SESSION_ID_SIZE;
+        let nonce_start = sequence_start + SEQUENCE_SIZE;
+        let mut session_id = [0u8; SESSION_ID_SIZE];
+        session_id.copy_from_slice(&datagram[session_id_start..sequence_start]);
+        let mut sequence = [0u8; SEQUENCE_SIZE];
+        sequence.copy_from_slice(&datagram[sequence_start..nonce_start]);
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&datagram[nonce_start..HEADER_SIZE]);
+
+        Ok(Header { key_id: datagram[CRC_SIZE], session_id: u32::from_be_bytes(session_id),
+            sequence: u32::from_be_bytes(sequence), nonce })
+    }
+
+    pub fn get_key_id(&self) -> u8 { self.key_id }
+    pub fn get_session_id(&self) -> u32 { self.session_id }
+    pub fn get_sequence(&self) -> u32 { self.sequence }
+    pub fn get_nonce(&self) -> &[u8; NONCE_SIZE] { &self.nonce }
+}
+
+// Sealed is the nonce followed by the encrypted data
+pub fn encode(key_id: u8, session_id: u32, sequence: u32, sealed: &[u8]) -> Vec<u8> {
+    let data = [&[key_id][..], &session_id.to_be_bytes()[..], &sequence.to_be_bytes()[..], sealed].concat();
+    [&hash(&data).to_be_bytes()[..], &data[..]].concat()
+}
+
+// Ties a datagram to its session, direction and position, so it can't be spliced or replayed elsewhere
+pub fn associated_data(session_id: u32, direction: u8, sequence: u32) -> Vec<u8> {
+    [&session_id.to_be_bytes()[..], &[direction], &sequence.to_be_bytes()[..]].concat()
+}
+
+// Whether a datagram without a session is sealed under one of the pre-shared keys,
+// checked before the server spends a session id, a port and a thread on its sender
+pub fn opens_session(keys: &KeyRing, datagram: &[u8]) -> bool {
+    let Ok(header) = Header::parse(datagram) else {
+        return false;
+    };
+    let Ok(key) = keys.get(header.key_id) else {
+        return false;
+    };
+    if header.session_id != 0 {
+        return false;
+    }
+
+    let associated_data = associated_data(0, CLIENT_TO_SERVER, header.sequence);
+    let payload = Payload { msg: &datagram[HEADER_SIZE..], aad: &associated_data };
+    CipherSuite::Aes256Gcm.new_cipher(key).decrypt(&header.nonce, payload).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+
+    const KEY: [u8; 32] = [7; 32];
+
+    fn seal(key_id: u8, key: &[u8; 32], session_id: u32, sequence: u32) -> Vec<u8> {
+        let nonce = [1u8; NONCE_SIZE];
+        let associated_data = associated_data(session_id, CLIENT_TO_SERVER, sequence);
+        let payload = Payload { msg: b"request", aad: &associated_data };
+        let encrypted = CipherSuite::Aes256Gcm.new_cipher(key).encrypt(&nonce, payload).unwrap();
+        encode(key_id, session_id, sequence, &[&nonce[..], &encrypted[..]].concat())
+    }
+
+    fn keys() -> KeyRing {
+        let mut keys = KeyRing::new(Duration::ZERO);
+        keys.add(1, KEY, None).unwrap();
+        keys
+    }
+
+    #[test]
+    fn headers_are_read_back() {
+        let datagram = encode(3, 42, 9, &[5u8; NONCE_SIZE + 4]);
+        let header = Header::parse(&datagram).unwrap();
+        assert_eq!(header.get_key_id(), 3);
+        assert_eq!(header.get_session_id(), 42);
+        assert_eq!(header.get_sequence(), 9);
+        assert_eq!(header.get_nonce(), &[5u8; NONCE_SIZE]);
+    }
+
+    #[test]
+    fn short_and_corrupted_datagrams_are_rejected() {
+        let mut datagram = encode(3, 42, 9, &[5u8; NONCE_SIZE]);
+        assert!(matches!(Header::parse(&datagram[..HEADER_SIZE - 1]), Err(DatagramError::TooShort(_))));
+        datagram[CRC_SIZE + KEY_ID_SIZE] ^= 1;
+        assert!(matches!(Header::parse(&datagram), Err(DatagramError::CrcMismatch)));
+    }
+
+    #[test]
+    fn only_datagrams_sealed_under_a_known_key_open_sessions() {
+        let keys = keys();
+        assert!(opens_session(&keys, &seal(1, &KEY, 0, 1)));
+        assert!(!opens_session(&keys, &seal(1, &[8; 32], 0, 1)));
+        assert!(!opens_session(&keys, &seal(2, &KEY, 0, 1)));
+        assert!(!opens_session(&keys, &seal(1, &KEY, 5, 1)));
+    }
+}
@@ -1,8 +1,14 @@
-use std::ffi::OsString;
-use std::fs::{self, File};
-use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
-use std::path::Path;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, Metadata, TryLockError};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use std::result::Result;
+use std::time::{Duration, UNIX_EPOCH};
+use protocol::bitmap::ChunkBitmap;
+use protocol::hex::{decode_hex, encode_hex};
+use sha2::{Digest, Sha256};
+use super::utils::ceil;
 
 #[derive(Debug)]
 pub enum FSError {
@@ -24,35 +30,118 @@ pub enum FSError {
     RemovingFailed,
     FlushFailed,
     SyncFailed,
+    SeekFailed,
+    RenameFailed,
+    PartialNotFound,
+    PartialStateInvalid,
+    PartialExists,
+    PartialInUse,
+    OffsetOutOfRange,
+    StatFailed,
+    InsufficientStorage,
+    PreallocationFailed,
 }
 
 impl From<FSError> for Error {
     fn from(e: FSError) -> Error {
         match e {
-            FSError::FileNotFound => Error::new(ErrorKind::Other, "File not found"),
-            FSError::PathNotExists => Error::new(ErrorKind::Other, "Path not found"),
-            FSError::FileCreationFailed => Error::new(ErrorKind::Other, "File creation failed"),
-            FSError::DirCreationFailed => Error::new(ErrorKind::Other, "Dir creation failed"),
-            FSError::FileReadFailed => Error::new(ErrorKind::Other, "File read failed"),
-            FSError::FileWriteFailed => Error::new(ErrorKind::Other, "File write failed"),
-            FSError::FileOpenFailed => Error::new(ErrorKind::Other, "File open failed"),
-            FSError::NotADirectory => Error::new(ErrorKind::Other, "Not a directory"),
-            FSError::NotHasParent => Error::new(ErrorKind::Other, "Not has a parent directory"),
-            FSError::NotAFile => Error::new(ErrorKind::Other, "Not a file"),
-            FSError::ReadDirFailed => Error::new(ErrorKind::Other, "Read dir failed"),
-            FSError::UnpackFailed => Error::new(ErrorKind::Other, "Unpack failed"),
-            FSError::FileAlreadyExists => Error::new(ErrorKind::Other, "File already exists"),
-            FSError::DirectoryFound => Error::new(ErrorKind::Other, "Directory found"),
-            FSError::MetadataFailed => Error::new(ErrorKind::Other, "Metadata failed"),
-            FSError::RemovingFailed => Error::new(ErrorKind::Other, "Removing failed"),
-            FSError::FlushFailed => Error::new(ErrorKind::Other, "Flush failed"),
-            FSError::SyncFailed => Error::new(ErrorKind::Other, "Sync failed"),
-        }
-    }
-}
-
-pub fn remove_file(path_str: &str) -> Result<(), FSError> {
-    let path = Path::new(path_str);
+            FSError::FileNotFound => Error::other("File not found"),
+            FSError::PathNotExists => Error::other("Path not found"),
+            FSError::FileCreationFailed => Error::other("File creation failed"),
+            FSError::DirCreationFailed => Error::other("Dir creation failed"),
+            FSError::FileReadFailed => Error::other("File read failed"),
+            FSError::FileWriteFailed => Error::other("File write failed"),
+            FSError::FileOpenFailed => Error::other("File open failed"),
+            FSError::NotADirectory => Error::other("Not a directory"),
+            FSError::NotHasParent => Error::other("Not has a parent directory"),
+            FSError::NotAFile => Error::other("Not a file"),
+            FSError::ReadDirFailed => Error::other("Read dir failed"),
+            FSError::UnpackFailed => Error::other("Unpack failed"),
+            FSError::FileAlreadyExists => Error::other("File already exists"),
+            FSError::DirectoryFound => Error::other("Directory found"),
+            FSError::MetadataFailed => Error::other("Metadata failed"),
+            FSError::RemovingFailed => Error::other("Removing failed"),
+            FSError::FlushFailed => Error::other("Flush failed"),
+            FSError::SyncFailed => Error::other("Sync failed"),
+            FSError::SeekFailed => Error::other("Seek failed"),
+            FSError::RenameFailed => Error::other("Rename failed"),
+            FSError::PartialNotFound => Error::other("Partial upload not found"),
+            FSError::PartialStateInvalid => Error::other("Partial upload state is invalid"),
+            FSError::PartialExists => Error::other("Upload in progress, use Continue"),
+            FSError::PartialInUse => Error::other("Upload in progress in another session"),
+            FSError::OffsetOutOfRange => Error::other("Offset is past the end of file"),
+            FSError::StatFailed => Error::other("Filesystem stat failed"),
+            FSError::InsufficientStorage => Error::other("Insufficient storage"),
+            FSError::PreallocationFailed => Error::other("Preallocation failed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOptions {
+    reserve: u64,
+    preallocate: bool,
+}
+
+impl WriteOptions {
+    pub fn new(reserve: u64, preallocate: bool) -> Self {
+        WriteOptions { reserve, preallocate }
+    }
+
+    pub fn get_preallocate(&self) -> bool { self.preallocate }
+}
+
+// Space unprivileged writers may still use on the filesystem holding the nearest existing ancestor
+#[cfg(unix)]
+fn get_available_space(path: &Path) -> Result<u64, FSError> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path.ancestors().find(|ancestor| ancestor.exists()).ok_or(FSError::StatFailed)?;
+    let path_str = CString::new(existing.as_os_str().as_bytes()).map_err(|_| FSError::StatFailed)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: the path is NUL terminated and stat lives through the call
+    if unsafe { libc::statvfs(path_str.as_ptr(), &mut stat) } != 0 {
+        return Err(FSError::StatFailed);
+    }
+
+    // The field widths differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok((stat.f_bavail as u64).saturating_mul(stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn get_available_space(_path: &Path) -> Result<u64, FSError> {
+    Ok(u64::MAX)
+}
+
+pub fn check_free_space(path: &Path, bytes: u64, options: &WriteOptions) -> Result<(), FSError> {
+    if get_available_space(path)? < bytes.saturating_add(options.reserve) {
+        return Err(FSError::InsufficientStorage);
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, size: u64) -> Result<(), FSError> {
+    use std::os::unix::io::AsRawFd;
+
+    let length = libc::off_t::try_from(size).map_err(|_| FSError::PreallocationFailed)?;
+    // SAFETY: the descriptor belongs to the file, which stays open through the call
+    match unsafe { libc::posix_fallocate(file.as_raw_fd(), 0, length) } {
+        0 => Ok(()),
+        libc::ENOSPC => Err(FSError::InsufficientStorage),
+        _ => Err(FSError::PreallocationFailed),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_file: &File, _size: u64) -> Result<(), FSError> {
+    Ok(())
+}
+
+pub fn remove_file(path: &Path) -> Result<(), FSError> {
     match fs::remove_file(path) {
         Ok(_) => Ok(()),
         Err(_) => Err(FSError::RemovingFailed),
@@ -61,13 +150,14 @@ pub fn remove_file(path_str: &str) -> Result<(), FSError> {
 
 pub struct FileChunkReader {
     reader: BufReader<File>,
-    chunk_size: usize
+    chunk_size: usize,
+    offset: u64,
+    length: u64,
+    remaining: u64,
 }
 
 impl FileChunkReader {
-    pub fn new(path_str: &str, chunk_size: usize) -> Result<Self, FSError> {
-        let path = Path::new(&path_str);
-
+    pub fn new(path: &Path, chunk_size: usize) -> Result<Self, FSError> {
         if !path.is_file() {
             return Err(FSError::FileNotFound);
         }
@@ -77,7 +167,8 @@ impl FileChunkReader {
             Err(_) => return Err(FSError::FileOpenFailed),
         };
 
-        Ok(FileChunkReader { reader: BufReader::new(file), chunk_size })
+        let length = file.metadata().map_err(|_| FSError::MetadataFailed)?.len();
+        Ok(FileChunkReader { reader: BufReader::new(file), chunk_size, offset: 0, length, remaining: length })
     }
 
     pub fn get_size(&self) -> Result<u64, FSError> {
@@ -86,16 +177,44 @@ impl FileChunkReader {
             Err(_) => Err(FSError::MetadataFailed),
         }
     }
+
+    // Limits reading to length bytes starting at offset, the length is cut at the end of file
+    pub fn set_range(&mut self, offset: u64, length: u64) -> Result<u64, FSError> {
+        let size = self.get_size()?;
+        if offset > size {
+            return Err(FSError::OffsetOutOfRange);
+        }
+
+        self.reader.seek(SeekFrom::Start(offset)).map_err(|_| FSError::SeekFailed)?;
+        self.offset = offset;
+        self.length = length.min(size - offset);
+        self.remaining = self.length;
+        Ok(self.length)
+    }
+
+    pub fn read_chunk(&mut self, chunk_id: u32) -> Result<Vec<u8>, FSError> {
+        let position = (chunk_id as u64).saturating_sub(1) * self.chunk_size as u64;
+        let offset = self.offset + position.min(self.length);
+        self.reader.seek(SeekFrom::Start(offset)).map_err(|_| FSError::SeekFailed)?;
+
+        let limit = (self.chunk_size as u64).min(self.length.saturating_sub(position));
+        let mut buffer = Vec::with_capacity(limit as usize);
+        (&mut self.reader).take(limit).read_to_end(&mut buffer)
+            .map_err(|_| FSError::FileReadFailed)?;
+        Ok(buffer)
+    }
 }
 
 impl Iterator for FileChunkReader {
     type Item = Result<Vec<u8>, FSError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut buffer = vec![0u8; self.chunk_size];
+        let limit = (self.chunk_size as u64).min(self.remaining) as usize;
+        let mut buffer = vec![0u8; limit];
         match self.reader.read(&mut buffer) {
             Ok(n) => {
                 buffer.truncate(n);
+                self.remaining -= n as u64;
                 Some(Ok(buffer))
             },
             Err(error) if error.kind() == ErrorKind::Interrupted => None,
@@ -104,13 +223,72 @@ impl Iterator for FileChunkReader {
     }
 }
 
+const PART_EXTENSION: &str = "part";
+const STATE_EXTENSION: &str = "state";
+const TEMP_EXTENSION: &str = "tmp";
+const STATE_SAVE_INTERVAL: u32 = 16;
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path_str = path.as_os_str().to_os_string();
+    path_str.push(".");
+    path_str.push(suffix);
+    PathBuf::from(path_str)
+}
+
+// Partial uploads sit hidden next to the file they become, so the final rename stays on one filesystem
+fn with_partial_suffix(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(".");
+    name.push(PART_EXTENSION);
+    path.with_file_name(name)
+}
+
+fn lock_part(file: &File) -> Result<(), FSError> {
+    match file.try_lock() {
+        Ok(()) => Ok(()),
+        Err(TryLockError::WouldBlock) => Err(FSError::PartialInUse),
+        Err(TryLockError::Error(_)) => Err(FSError::FileOpenFailed),
+    }
+}
+
+// Makes a rename inside the directory survive a crash
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<(), FSError> {
+    File::open(path).and_then(|dir| dir.sync_all()).map_err(|_| FSError::SyncFailed)
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<(), FSError> {
+    Ok(())
+}
+
+fn chunk_digest(chunk_id: u32, chunk: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(chunk_id.to_be_bytes());
+    hasher.update(chunk);
+    hasher.finalize().into()
+}
+
+// Uploads go to .<name>.part, what has been received so far is kept in .<name>.part.state
 pub struct FileChunkWriter { // TODO optimum
     writer: BufWriter<File>,
+    path: PathBuf,
+    part_path: PathBuf,
+    state_path: PathBuf,
+    size: u64,
+    chunk_size: usize,
+    received: ChunkBitmap,
+    // Length of the part file, what the disk usage scans count for it
+    length: u64,
+    // XOR of per-chunk digests, so it does not depend on the order chunks came in
+    content_hash: [u8; 32],
+    unsaved_chunks: u32,
 }
 
 impl FileChunkWriter {
-    pub fn new(path_str: &str) -> Result<Self, FSError> { // TODO Rewrite error handling
-        let path = Path::new(&path_str);
+    // TODO Rewrite error handling
+    pub fn new(path: &Path, size: u64, chunk_size: usize, preallocated: bool) -> Result<Self, FSError> {
         if path.is_file() {
             return Err(FSError::FileAlreadyExists);
         }
@@ -132,38 +310,310 @@ impl FileChunkWriter {
             fs::create_dir_all(parent).map_err(|_| FSError::DirCreationFailed)?;
         }
 
-        let file = match File::create(path) {
+        let part_path = with_partial_suffix(path);
+        let file = match File::options().write(true).create_new(true).open(&part_path) {
             Ok(f) => f,
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => return Err(FSError::PartialExists),
             Err(_) => return Err(FSError::FileCreationFailed)
         };
-        Ok(FileChunkWriter { writer: BufWriter::new(file) })
+        lock_part(&file)?;
+
+        let mut length = 0;
+        if preallocated {
+            if let Err(error) = preallocate(&file, size) {
+                let _ = fs::remove_file(&part_path);
+                return Err(error);
+            }
+            length = size;
+        }
+
+        let chunk_count = ceil(size, chunk_size as u64);
+        let writer = FileChunkWriter { writer: BufWriter::new(file), path: path.to_path_buf(),
+            state_path: with_suffix(&part_path, STATE_EXTENSION), part_path, size, chunk_size,
+            received: ChunkBitmap::new(chunk_count), length, content_hash: [0u8; 32], unsaved_chunks: 0 };
+        writer.save_state()?;
+        Ok(writer)
     }
 
-    pub fn write_chunk(&mut self, chunk: &[u8]) -> Result<(), FSError> {
-        Ok(self.writer.write_all(chunk).map_err(|_| FSError::FileWriteFailed)?)
+    pub fn resume(path: &Path, size: u64, chunk_size: usize) -> Result<Self, FSError> {
+        if path.exists() {
+            return Err(FSError::FileAlreadyExists);
+        }
+
+        let part_path = with_partial_suffix(path);
+        let state_path = with_suffix(&part_path, STATE_EXTENSION);
+        if !part_path.is_file() || !state_path.is_file() {
+            return Err(FSError::PartialNotFound);
+        }
+
+        let state_str = fs::read_to_string(&state_path).map_err(|_| FSError::FileReadFailed)?;
+        let state: HashMap<&str, &str> = state_str.lines().filter_map(|line| line.split_once('=')).collect();
+        let chunk_count = ceil(size, chunk_size as u64);
+        if state.get("path") != Some(&path.to_string_lossy().as_ref())
+            || state.get("size") != Some(&size.to_string().as_str())
+            || state.get("chunk_size") != Some(&chunk_size.to_string().as_str()) {
+            return Err(FSError::PartialStateInvalid);
+        }
+
+        let content_hash = match state.get("hash").and_then(|hash| decode_hex(hash)) {
+            Some(hash) => <[u8; 32]>::try_from(hash).map_err(|_| FSError::PartialStateInvalid)?,
+            None => return Err(FSError::PartialStateInvalid),
+        };
+
+        let words = match state.get("chunks").and_then(|chunks| decode_hex(chunks)) {
+            Some(bytes) if bytes.len() % 8 == 0 => bytes.chunks(8)
+                .map(|word| u64::from_be_bytes(word.try_into().unwrap_or([0u8; 8]))).collect(),
+            _ => return Err(FSError::PartialStateInvalid),
+        };
+        let received = ChunkBitmap::from_words(chunk_count, words).ok_or(FSError::PartialStateInvalid)?;
+
+        let mut file = File::options().read(true).write(true).open(&part_path)
+            .map_err(|_| FSError::FileOpenFailed)?;
+        lock_part(&file)?;
+        let length = file.metadata().map_err(|_| FSError::MetadataFailed)?.len();
+
+        let mut verified_hash = [0u8; 32];
+        let mut chunk = Vec::with_capacity(chunk_size);
+        for chunk_id in (1..=chunk_count).filter(|chunk_id| received.contains(*chunk_id)) {
+            let offset = (chunk_id as u64 - 1) * chunk_size as u64;
+            file.seek(SeekFrom::Start(offset)).map_err(|_| FSError::SeekFailed)?;
+            chunk.clear();
+            (&mut file).take(chunk_size as u64).read_to_end(&mut chunk).map_err(|_| FSError::FileReadFailed)?;
+            for (byte, digest_byte) in verified_hash.iter_mut().zip(chunk_digest(chunk_id, &chunk)) {
+                *byte ^= digest_byte;
+            }
+        }
+
+        if verified_hash != content_hash {
+            return Err(FSError::PartialStateInvalid);
+        }
+
+        Ok(FileChunkWriter { writer: BufWriter::new(file), path: path.to_path_buf(), part_path, state_path, size,
+            chunk_size, received, length, content_hash, unsaved_chunks: 0 })
+    }
+
+    pub fn write_at(&mut self, chunk_id: u32, chunk: &[u8]) -> Result<(), FSError> {
+        let offset = (chunk_id as u64).saturating_sub(1) * self.chunk_size as u64;
+        self.writer.seek(SeekFrom::Start(offset)).map_err(|_| FSError::SeekFailed)?;
+        self.writer.write_all(chunk).map_err(|_| FSError::FileWriteFailed)?;
+        self.length = self.length.max(offset + chunk.len() as u64);
+
+        if self.received.set(chunk_id) {
+            for (byte, digest_byte) in self.content_hash.iter_mut().zip(chunk_digest(chunk_id, chunk)) {
+                *byte ^= digest_byte;
+            }
+            self.unsaved_chunks += 1;
+        }
+
+        if self.unsaved_chunks >= STATE_SAVE_INTERVAL {
+            self.suspend()?;
+        }
+
+        Ok(())
+    }
+
+    // Data is synced before the state, so the state never claims chunks that are not on disk
+    pub fn suspend(&mut self) -> Result<(), FSError> {
+        self.writer.flush().map_err(|_| FSError::FlushFailed)?;
+        self.writer.get_ref().sync_data().map_err(|_| FSError::SyncFailed)?;
+        self.save_state()?;
+        self.unsaved_chunks = 0;
+        Ok(())
     }
 
     pub fn finish(&mut self) -> Result<(), FSError> {
         self.writer.flush().map_err(|_| FSError::FlushFailed)?;
-        Ok(self.writer.get_ref().sync_all().map_err(|_| FSError::SyncFailed)?)
+        self.writer.get_ref().sync_all().map_err(|_| FSError::SyncFailed)?;
+        // Unlike a rename, the link fails when another upload put a file there first
+        match fs::hard_link(&self.part_path, &self.path) {
+            Ok(()) => (),
+            Err(error) if error.kind() == ErrorKind::AlreadyExists => return Err(FSError::FileAlreadyExists),
+            Err(_) => return Err(FSError::RenameFailed),
+        }
+        remove_file(&self.part_path)?;
+        remove_file(&self.state_path)?;
+        match self.path.parent() {
+            Some(parent) => sync_dir(parent),
+            None => Ok(()),
+        }
+    }
+
+    pub fn discard(&mut self) -> Result<(), FSError> {
+        remove_file(&self.part_path)?;
+        remove_file(&self.state_path)
+    }
+
+    pub fn get_received_chunks(&self) -> &ChunkBitmap { &self.received }
+
+    pub fn get_length(&self) -> u64 { self.length }
+
+    pub fn get_growth(&self, chunk_id: u32, chunk_length: usize) -> u64 {
+        let offset = (chunk_id as u64).saturating_sub(1) * self.chunk_size as u64;
+        (offset + chunk_length as u64).saturating_sub(self.length)
+    }
+
+    fn save_state(&self) -> Result<(), FSError> {
+        let chunks: Vec<u8> = self.received.get_words().iter().flat_map(|word| word.to_be_bytes()).collect();
+        let state_str = format!("path={}\nsize={}\nchunk_size={}\nhash={}\nchunks={}\n",
+                                self.path.to_string_lossy(), self.size, self.chunk_size,
+                                encode_hex(&self.content_hash), encode_hex(&chunks));
+
+        let temp_path = with_suffix(&self.state_path, TEMP_EXTENSION);
+        fs::write(&temp_path, state_str).map_err(|_| FSError::FileWriteFailed)?;
+        fs::rename(&temp_path, &self.state_path).map_err(|_| FSError::RenameFailed)
+    }
+}
+
+pub enum FSEntryKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+pub struct FSEntry {
+    name: OsString,
+    kind: FSEntryKind,
+    size: u64,
+    modified: u64,
+}
+
+impl FSEntry {
+    fn new(name: OsString, metadata: &Metadata) -> Self {
+        let file_type = metadata.file_type();
+        let kind = if file_type.is_symlink() {
+            FSEntryKind::Symlink
+        } else if file_type.is_dir() {
+            FSEntryKind::Directory
+        } else if file_type.is_file() {
+            FSEntryKind::File
+        } else {
+            FSEntryKind::Other
+        };
+
+        let modified = match metadata.modified().map(|time| time.duration_since(UNIX_EPOCH)) {
+            Ok(Ok(duration)) => duration.as_secs(),
+            _ => 0,
+        };
+
+        FSEntry { name, kind, size: metadata.len(), modified }
+    }
+
+    pub fn get_name(&self) -> &OsString { &self.name }
+    pub fn get_kind(&self) -> &FSEntryKind { &self.kind }
+    pub fn get_size(&self) -> u64 { self.size }
+    pub fn get_modified(&self) -> u64 { self.modified }
+}
+
+pub struct DiskUsage {
+    bytes: u64,
+    files: u64,
+}
+
+impl DiskUsage {
+    pub fn get_bytes(&self) -> u64 { self.bytes }
+    pub fn get_files(&self) -> u64 { self.files }
+}
+
+pub fn is_reserved_name(name: &OsStr) -> bool {
+    let name = name.to_string_lossy();
+    let Some(name) = name.strip_prefix('.') else { return false };
+    let part = format!(".{}", PART_EXTENSION);
+    let state = format!("{}.{}", part, STATE_EXTENSION);
+    let temp = format!("{}.{}", state, TEMP_EXTENSION);
+    [part, state, temp].iter().any(|suffix| name.len() > suffix.len() && name.ends_with(suffix.as_str()))
+}
+
+fn is_partial(path: &Path) -> bool {
+    path.file_name().is_some_and(is_reserved_name)
+}
+
+fn sweep_partial(path: &Path, max_age: Duration) -> Result<u64, FSError> {
+    let metadata = fs::symlink_metadata(path).map_err(|_| FSError::MetadataFailed)?;
+    let age = metadata.modified().ok().and_then(|modified| modified.elapsed().ok()).unwrap_or_default();
+    if age < max_age {
+        return Ok(0);
+    }
+
+    let name = path.to_string_lossy();
+    let part_suffix = format!(".{}", PART_EXTENSION);
+    if name.ends_with(&part_suffix) {
+        let file = File::open(path).map_err(|_| FSError::FileOpenFailed)?;
+        if let Err(FSError::PartialInUse) = lock_part(&file) {
+            return Ok(0);
+        }
+        remove_file(path)?;
+        return Ok(1 + remove_file(&with_suffix(path, STATE_EXTENSION)).map_or(0, |_| 1));
+    }
+
+    // States and their updates go once their part file is gone
+    let part_end = name.rfind(&part_suffix).map_or(0, |index| index + part_suffix.len());
+    if Path::new(&name[..part_end]).exists() {
+        return Ok(0);
     }
+    remove_file(path)?;
+    Ok(1)
 }
 
-pub fn get_fs_entries(path_str: &String) -> Result<Vec<OsString>, FSError> {
-    let path = Path::new(&path_str);
+pub fn sweep_partials(root: &Path, max_age: Duration) -> Result<u64, FSError> {
+    let mut removed = 0;
+    let mut directories = vec![root.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let read_dir = fs::read_dir(&directory).map_err(|_| FSError::ReadDirFailed)?;
+        for entry_result in read_dir {
+            let entry = entry_result.map_err(|_| FSError::UnpackFailed)?;
+            let file_type = entry.file_type().map_err(|_| FSError::MetadataFailed)?;
+            if file_type.is_dir() {
+                directories.push(entry.path());
+            } else if file_type.is_file() && is_partial(&entry.path()) {
+                removed += sweep_partial(&entry.path(), max_age)?;
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+// Partial uploads count with their bytes but not as files yet, symlinks are not followed
+pub fn get_disk_usage(path: &Path) -> Result<DiskUsage, FSError> {
+    let mut usage = DiskUsage { bytes: 0, files: 0 };
+    if !path.is_dir() {
+        return Ok(usage);
+    }
+
+    let mut directories = vec![path.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        let read_dir = fs::read_dir(&directory).map_err(|_| FSError::ReadDirFailed)?;
+        for entry_result in read_dir {
+            let entry = entry_result.map_err(|_| FSError::UnpackFailed)?;
+            let metadata = entry.metadata().map_err(|_| FSError::MetadataFailed)?;
+            if metadata.is_dir() {
+                directories.push(entry.path());
+            } else if metadata.is_file() {
+                usage.bytes += metadata.len();
+                if !is_partial(&entry.path()) {
+                    usage.files += 1;
+                }
+            }
+        }
+    }
+
+    Ok(usage)
+}
+
+pub fn get_fs_entries(path: &Path) -> Result<Vec<FSEntry>, FSError> {
     if path.exists() {
-        let mut entries: Vec<OsString> = Vec::new();
+        let mut entries: Vec<FSEntry> = Vec::new();
         if path.is_dir() {
             let read_dir = fs::read_dir(path).map_err(|_| FSError::ReadDirFailed)?;
             for entry_result in read_dir {
                 let entry = entry_result.map_err(|_| FSError::UnpackFailed)?;
-                let path = entry.path();
-                let filename_osstr = match path.file_name() {
-                    Some(osstr) => osstr,
-                    None => return Err(FSError::UnpackFailed),
-                };
+                if is_partial(&entry.path()) {
+                    continue;
+                }
 
-                entries.push(filename_osstr.to_os_string());
+                let metadata = entry.metadata().map_err(|_| FSError::MetadataFailed)?;
+                entries.push(FSEntry::new(entry.file_name(), &metadata));
             }
 
             return Ok(entries);
@@ -173,4 +623,197 @@ pub fn get_fs_entries(path_str: &String) -> Result<Vec<OsString>, FSError> {
     }
 
     Err(FSError::PathNotExists)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::temp_dir;
+    use super::*;
+
+    const CHUNK_SIZE: usize = 4;
+
+    #[test]
+    fn resume_picks_up_the_received_chunks() {
+        let dir = temp_dir("filesystem-resume");
+        let path = dir.join("file.bin");
+        let mut writer = FileChunkWriter::new(&path, 10, CHUNK_SIZE, false).unwrap();
+        writer.write_at(3, b"ij").unwrap();
+        writer.write_at(1, b"abcd").unwrap();
+        writer.suspend().unwrap();
+        drop(writer);
+
+        let mut writer = FileChunkWriter::resume(&path, 10, CHUNK_SIZE).unwrap();
+        assert_eq!(writer.get_received_chunks().missing(10), vec![2]);
+        writer.write_at(2, b"efgh").unwrap();
+        assert!(writer.get_received_chunks().is_complete());
+        writer.finish().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abcdefghij");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resume_rejects_changed_parts_and_sizes() {
+        let dir = temp_dir("filesystem-tampered");
+        let path = dir.join("file.bin");
+        let mut writer = FileChunkWriter::new(&path, 10, CHUNK_SIZE, false).unwrap();
+        writer.write_at(1, b"abcd").unwrap();
+        writer.suspend().unwrap();
+        drop(writer);
+
+        assert!(matches!(FileChunkWriter::resume(&path, 11, CHUNK_SIZE), Err(FSError::PartialStateInvalid)));
+        fs::write(with_partial_suffix(&path), b"abcx").unwrap();
+        assert!(matches!(FileChunkWriter::resume(&path, 10, CHUNK_SIZE), Err(FSError::PartialStateInvalid)));
+        assert!(matches!(FileChunkWriter::resume(&dir.join("other.bin"), 10, CHUNK_SIZE),
+                         Err(FSError::PartialNotFound)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn content_hash_does_not_depend_on_chunk_order() {
+        let dir = temp_dir("filesystem-order");
+        let mut first = FileChunkWriter::new(&dir.join("a"), 8, CHUNK_SIZE, false).unwrap();
+        first.write_at(1, b"abcd").unwrap();
+        first.write_at(2, b"efgh").unwrap();
+        let mut second = FileChunkWriter::new(&dir.join("b"), 8, CHUNK_SIZE, false).unwrap();
+        second.write_at(2, b"efgh").unwrap();
+        second.write_at(1, b"abcd").unwrap();
+
+        assert_eq!(first.content_hash, second.content_hash);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn chunks_are_read_from_the_range_only() {
+        let dir = temp_dir("filesystem-range");
+        let path = dir.join("file.bin");
+        fs::write(&path, b"abcdefghij").unwrap();
+        let mut reader = FileChunkReader::new(&path, CHUNK_SIZE).unwrap();
+
+        assert_eq!(reader.set_range(3, 5).unwrap(), 5);
+        assert_eq!(reader.read_chunk(1).unwrap(), b"defg");
+        assert_eq!(reader.read_chunk(2).unwrap(), b"h");
+        assert!(reader.read_chunk(3).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ranges_are_cut_at_the_end_of_file() {
+        let dir = temp_dir("filesystem-range-end");
+        let path = dir.join("file.bin");
+        fs::write(&path, b"abcdefghij").unwrap();
+        let mut reader = FileChunkReader::new(&path, CHUNK_SIZE).unwrap();
+
+        assert_eq!(reader.set_range(8, 100).unwrap(), 2);
+        assert_eq!(reader.read_chunk(1).unwrap(), b"ij");
+        assert_eq!(reader.set_range(10, 5).unwrap(), 0);
+        assert!(reader.read_chunk(1).unwrap().is_empty());
+        assert!(matches!(reader.set_range(11, 1), Err(FSError::OffsetOutOfRange)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn free_space_has_to_cover_the_reserve() {
+        let dir = temp_dir("filesystem-free-space");
+
+        assert!(check_free_space(&dir, 0, &WriteOptions::new(0, false)).is_ok());
+        assert!(check_free_space(&dir.join("missing/file"), 1, &WriteOptions::new(0, false)).is_ok());
+        assert!(matches!(check_free_space(&dir, u64::MAX, &WriteOptions::new(0, false)),
+                         Err(FSError::InsufficientStorage)));
+        assert!(matches!(check_free_space(&dir, 1, &WriteOptions::new(u64::MAX, false)),
+                         Err(FSError::InsufficientStorage)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn preallocated_parts_do_not_grow() {
+        let dir = temp_dir("filesystem-preallocate");
+        let mut preallocated = FileChunkWriter::new(&dir.join("a"), 10, CHUNK_SIZE, true).unwrap();
+        let mut growing = FileChunkWriter::new(&dir.join("b"), 10, CHUNK_SIZE, false).unwrap();
+
+        assert_eq!(preallocated.get_length(), 10);
+        assert_eq!(preallocated.get_growth(3, 2), 0);
+        #[cfg(target_os = "linux")]
+        assert_eq!(fs::metadata(with_partial_suffix(&dir.join("a"))).unwrap().len(), 10);
+        preallocated.write_at(3, b"ij").unwrap();
+        assert_eq!(preallocated.get_length(), 10);
+
+        assert_eq!(growing.get_length(), 0);
+        assert_eq!(growing.get_growth(3, 2), 10);
+        growing.write_at(3, b"ij").unwrap();
+        assert_eq!(growing.get_growth(1, 4), 0);
+        assert_eq!(growing.get_length(), 10);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finish_moves_the_part_into_place() {
+        let dir = temp_dir("filesystem-finish");
+        let path = dir.join("file.bin");
+        let mut writer = FileChunkWriter::new(&path, 4, CHUNK_SIZE, false).unwrap();
+        writer.write_at(1, b"abcd").unwrap();
+        assert!(!path.exists());
+        assert!(get_fs_entries(&dir).unwrap().is_empty());
+
+        writer.finish().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"abcd");
+        assert!(!with_partial_suffix(&path).exists());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn finish_does_not_replace_a_file_put_there_meanwhile() {
+        let dir = temp_dir("filesystem-collision");
+        let path = dir.join("file.bin");
+        let mut writer = FileChunkWriter::new(&path, 4, CHUNK_SIZE, false).unwrap();
+        writer.write_at(1, b"abcd").unwrap();
+        fs::write(&path, b"other").unwrap();
+
+        assert!(matches!(writer.finish(), Err(FSError::FileAlreadyExists)));
+        assert_eq!(fs::read(&path).unwrap(), b"other");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parts_are_not_overwritten_or_shared() {
+        let dir = temp_dir("filesystem-locked");
+        let path = dir.join("file.bin");
+        let mut writer = FileChunkWriter::new(&path, 4, CHUNK_SIZE, false).unwrap();
+        writer.write_at(1, b"abcd").unwrap();
+        writer.suspend().unwrap();
+
+        assert!(matches!(FileChunkWriter::new(&path, 4, CHUNK_SIZE, false), Err(FSError::PartialExists)));
+        assert!(matches!(FileChunkWriter::resume(&path, 4, CHUNK_SIZE), Err(FSError::PartialInUse)));
+        drop(writer);
+        assert!(FileChunkWriter::resume(&path, 4, CHUNK_SIZE).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_exact_part_names_are_reserved() {
+        assert!(is_reserved_name(OsStr::new(".file.bin.part")));
+        assert!(is_reserved_name(OsStr::new(".file.bin.part.state")));
+        assert!(is_reserved_name(OsStr::new(".file.bin.part.state.tmp")));
+        assert!(!is_reserved_name(OsStr::new("file.bin.part")));
+        assert!(!is_reserved_name(OsStr::new(".part")));
+        assert!(!is_reserved_name(OsStr::new(".bashrc")));
+    }
+
+    #[test]
+    fn sweep_removes_abandoned_parts_only() {
+        let dir = temp_dir("filesystem-sweep");
+        let mut abandoned = FileChunkWriter::new(&dir.join("old.bin"), 4, CHUNK_SIZE, false).unwrap();
+        abandoned.suspend().unwrap();
+        drop(abandoned);
+        let _active = FileChunkWriter::new(&dir.join("active.bin"), 4, CHUNK_SIZE, false).unwrap();
+        fs::write(dir.join("kept.bin"), b"data").unwrap();
+
+        assert_eq!(sweep_partials(&dir, Duration::from_secs(3600)).unwrap(), 0);
+        assert_eq!(sweep_partials(&dir, Duration::ZERO).unwrap(), 2);
+        assert!(!with_partial_suffix(&dir.join("old.bin")).exists());
+        assert!(with_partial_suffix(&dir.join("active.bin")).exists());
+        assert!(dir.join("kept.bin").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
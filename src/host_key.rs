@@ -0,0 +1,106 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::Path;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use ed25519_dalek::{Signer, SigningKey};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use protocol::enums::{HOST_KEY_SIZE, SIGNATURE_SIZE};
+use protocol::hex::encode_hex;
+use protocol::known_hosts::host_signature_message;
+use super::keys::decode_key;
+
+#[derive(Debug)]
+pub enum HostKeyError {
+    ReadFailed(String),
+    WriteFailed(String),
+    InvalidKey(String),
+}
+
+impl From<HostKeyError> for Error {
+    fn from(error: HostKeyError) -> Error {
+        match error {
+            HostKeyError::ReadFailed(path) => Error::other(format!("Host key {} read failed", path)),
+            HostKeyError::WriteFailed(path) => Error::other(format!("Host key {} write failed", path)),
+            HostKeyError::InvalidKey(path) => {
+                Error::other(format!("Host key {} should be 32 bytes of hex or base64", path))
+            }
+        }
+    }
+}
+
+// Only the owner may read a freshly written key
+#[cfg(unix)]
+fn create_private(path: &Path) -> std::io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+#[derive(Clone)]
+pub struct HostKey {
+    key: SigningKey,
+}
+
+impl HostKey {
+    // None until the server generated its key
+    pub fn load(path: &Path) -> Result<Option<Self>, HostKeyError> {
+        let path_str = path.display().to_string();
+        match fs::read_to_string(path) {
+            Ok(key_str) => {
+                let seed = decode_key(&key_str).map_err(|_| HostKeyError::InvalidKey(path_str))?;
+                Ok(Some(HostKey { key: SigningKey::from_bytes(&seed) }))
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => Ok(None),
+            Err(_) => Err(HostKeyError::ReadFailed(path_str)),
+        }
+    }
+
+    pub fn load_or_generate(path: &Path) -> Result<Self, HostKeyError> {
+        if let Some(host_key) = HostKey::load(path)? {
+            return Ok(host_key);
+        }
+
+        let path_str = path.display().to_string();
+        let mut seed = [0u8; HOST_KEY_SIZE];
+        OsRng.fill_bytes(&mut seed);
+        let mut file = create_private(path).map_err(|_| HostKeyError::WriteFailed(path_str.clone()))?;
+        writeln!(file, "{}", encode_hex(&seed)).map_err(|_| HostKeyError::WriteFailed(path_str))?;
+        Ok(HostKey { key: SigningKey::from_bytes(&seed) })
+    }
+
+    pub fn get_public_key(&self) -> [u8; HOST_KEY_SIZE] { self.key.verifying_key().to_bytes() }
+
+    // Same form as OpenSSH prints, so operators can compare it out of band
+    pub fn get_fingerprint(&self) -> String {
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(self.get_public_key())))
+    }
+
+    pub fn sign(&self, transcript: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        self.key.sign(&host_signature_message(transcript)).to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::temp_dir;
+    use super::*;
+
+    #[test]
+    fn keys_are_generated_once() {
+        let dir = temp_dir("host-key");
+        let path = dir.join("host_key");
+        assert!(HostKey::load(&path).unwrap().is_none());
+        assert!(!path.exists());
+
+        let generated = HostKey::load_or_generate(&path).unwrap();
+        assert_eq!(HostKey::load(&path).unwrap().unwrap().get_public_key(), generated.get_public_key());
+        assert_eq!(HostKey::load_or_generate(&path).unwrap().get_public_key(), generated.get_public_key());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
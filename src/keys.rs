@@ -0,0 +1,136 @@
+use std::env;
+use std::fs;
+use std::io::Error;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use protocol::hex::decode_hex;
+
+pub const KEY_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub enum KeyError {
+    ReadFailed(String),
+    EnvNotSet(String),
+    InvalidEncoding,
+    InvalidLength,
+    DuplicateId(u8),
+    UnknownId(u8),
+    Retired(u8),
+    Mismatch(u8),
+}
+
+impl From<KeyError> for Error {
+    fn from(error: KeyError) -> Error {
+        match error {
+            KeyError::ReadFailed(path) => Error::other(format!("Key file {} read failed", path)),
+            KeyError::EnvNotSet(name) => Error::other(format!("Key variable {} is not set", name)),
+            KeyError::InvalidEncoding => Error::other("Key should be hex or base64"),
+            KeyError::InvalidLength => Error::other("Key should be 32 bytes long"),
+            KeyError::DuplicateId(id) => Error::other(format!("Key id {} is used twice", id)),
+            KeyError::UnknownId(id) => Error::other(format!("Unknown key id {}", id)),
+            KeyError::Retired(id) => Error::other(format!("Key {} is retired", id)),
+            KeyError::Mismatch(id) => Error::other(format!("Key {} is not the one the session started with", id)),
+        }
+    }
+}
+
+// 64 hex digits or standard base64, surrounding whitespace is ignored
+pub fn decode_key(key_str: &str) -> Result<[u8; KEY_SIZE], KeyError> {
+    let key_str = key_str.trim();
+    let key_bytes = match decode_hex(key_str) {
+        Some(key_bytes) => key_bytes,
+        None => STANDARD.decode(key_str).map_err(|_| KeyError::InvalidEncoding)?,
+    };
+
+    <[u8; KEY_SIZE]>::try_from(key_bytes).map_err(|_| KeyError::InvalidLength)
+}
+
+pub fn read_key_file(path: &Path) -> Result<[u8; KEY_SIZE], KeyError> {
+    let key_str = fs::read_to_string(path).map_err(|_| KeyError::ReadFailed(path.display().to_string()))?;
+    decode_key(&key_str)
+}
+
+pub fn read_key_env(name: &str) -> Result<[u8; KEY_SIZE], KeyError> {
+    let key_str = env::var(name).map_err(|_| KeyError::EnvNotSet(name.to_string()))?;
+    decode_key(&key_str)
+}
+
+#[derive(Clone)]
+struct Key {
+    id: u8,
+    key: [u8; KEY_SIZE],
+    retired_at: Option<SystemTime>,
+}
+
+// Pre-shared keys by the id clients put in the clear header, a retired key still works for the grace period
+#[derive(Clone)]
+pub struct KeyRing {
+    keys: Vec<Key>,
+    grace_period: Duration,
+}
+
+impl KeyRing {
+    pub fn new(grace_period: Duration) -> Self {
+        KeyRing { keys: Vec::new(), grace_period }
+    }
+
+    pub fn add(&mut self, id: u8, key: [u8; KEY_SIZE], retired_at: Option<SystemTime>) -> Result<(), KeyError> {
+        if self.keys.iter().any(|key| key.id == id) {
+            return Err(KeyError::DuplicateId(id));
+        }
+
+        self.keys.push(Key { id, key, retired_at });
+        Ok(())
+    }
+
+    pub fn get(&self, id: u8) -> Result<&[u8; KEY_SIZE], KeyError> {
+        let key = self.keys.iter().find(|key| key.id == id).ok_or(KeyError::UnknownId(id))?;
+        if let Some(retired_at) = key.retired_at && SystemTime::now() >= retired_at + self.grace_period {
+            return Err(KeyError::Retired(id));
+        }
+
+        Ok(&key.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn keys_decode_from_hex_and_base64() {
+        let key = decode_key(KEY_HEX).unwrap();
+        assert_eq!(key[31], 0x1f);
+        assert_eq!(decode_key(&format!(" {}\n", STANDARD.encode(key))).unwrap(), key);
+        assert!(matches!(decode_key("0001"), Err(KeyError::InvalidLength)));
+        assert!(matches!(decode_key("not a key!"), Err(KeyError::InvalidEncoding)));
+    }
+
+    #[test]
+    fn retired_keys_work_until_the_grace_period_ends() {
+        let now = SystemTime::now();
+        let mut keys = KeyRing::new(Duration::from_secs(3600));
+        keys.add(1, [1; KEY_SIZE], None).unwrap();
+        keys.add(2, [2; KEY_SIZE], Some(now - Duration::from_secs(60))).unwrap();
+        keys.add(3, [3; KEY_SIZE], Some(now - Duration::from_secs(7200))).unwrap();
+        keys.add(4, [4; KEY_SIZE], Some(now + Duration::from_secs(60))).unwrap();
+
+        assert_eq!(keys.get(1).unwrap(), &[1; KEY_SIZE]);
+        assert_eq!(keys.get(2).unwrap(), &[2; KEY_SIZE]);
+        assert!(matches!(keys.get(3), Err(KeyError::Retired(3))));
+        assert_eq!(keys.get(4).unwrap(), &[4; KEY_SIZE]);
+        assert!(matches!(keys.get(5), Err(KeyError::UnknownId(5))));
+    }
+
+    #[test]
+    fn without_grace_retired_keys_stop_at_once() {
+        let mut keys = KeyRing::new(Duration::ZERO);
+        keys.add(1, [1; KEY_SIZE], Some(SystemTime::now())).unwrap();
+        assert!(matches!(keys.get(1), Err(KeyError::Retired(1))));
+        assert!(matches!(keys.add(1, [2; KEY_SIZE], None), Err(KeyError::DuplicateId(1))));
+    }
+}
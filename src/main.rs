@@ -3,26 +3,169 @@ mod filesystem;
 mod session;
 mod utils;
 mod cypher;
+mod cipher;
+mod reliability;
+mod sandbox;
+mod config;
+mod cli;
+mod logger;
+mod keys;
+mod users;
+mod authorized_keys;
+mod host_key;
+mod access;
+mod roles;
+mod quotas;
+mod datagram;
+mod rate_limit;
+#[cfg(test)]
+mod test_utils;
 
 use network::{Server};
-use std::io::Result;
-use session::Session;
-use cypher::Cypher;
+use std::io::{stdin, Error, Result};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+use clap::Parser;
+use log::{error, info, warn};
+use session::{Session, SessionSettings};
+use sandbox::Sandbox;
+use config::Config;
+use cli::{Cli, Command};
+use cypher::CypherOptions;
+
+const PARTIAL_SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 pub fn main() -> Result<()> {
-    let key_str = "SUPER_SECRET_KEY1125133111444411";
-    let key = match <&[u8; 32]>::try_from(key_str.as_bytes()) {
-        Ok(key) => key,
-        Err(_) => panic!("Key is invalid"),
+    let (args, check_only) = match Cli::parse().into_command() {
+        Command::Serve(args) => (args, false),
+        Command::Check(args) => (args, true),
+        Command::HashPassword => return hash_password(),
     };
-    let cypher = Cypher::new(key);
-    let server = Server::new("1998")?;
+
+    let mut config = Config::load(args.config.as_deref())?;
+    args.apply(&mut config);
+    logger::init(config.get_log_level()?).map_err(|_| Error::other("Logger is already set"))?;
+    if check_only {
+        return check(&config);
+    }
+
+    let sandbox = Sandbox::new(&config.storage.root, config.get_symlink_policy()?)?;
+    let quotas = config.get_quotas(sandbox.get_root())?;
+    let settings = SessionSettings {
+        keys: config.load_keys()?,
+        sandbox,
+        limits: config.get_limits(),
+        timeouts: config.get_timeouts()?,
+        cypher_options: CypherOptions::new(config.get_cipher_suites()?, config.get_rekey_limits()),
+        host_key: config.load_host_key()?,
+        users: config.load_users()?,
+        authorized_keys: config.load_authorized_keys()?,
+        access: config.get_access_policy()?,
+        roles: config.load_roles()?,
+        quotas,
+        write_options: config.get_write_options(),
+    };
+    if settings.users.is_none() && settings.authorized_keys.is_none() {
+        warn!("No users or authorized keys configured, clients are not authenticated");
+    }
+    let bind_addrs = config.get_bind_addrs()?;
+    let session_ports = config.get_session_ports()?;
+
+    if let Some(roles) = &settings.roles {
+        roles.watch(config.get_policy_reload());
+    }
+    if let Some(max_age) = config.get_partial_expiry() {
+        let root = settings.sandbox.get_root().to_path_buf();
+        thread::spawn(move || sweep_partials(root, max_age));
+    }
+    info!("Host key fingerprint {}", settings.host_key.get_fingerprint());
+    info!("Serving files from {}", settings.sandbox.get_root().display());
+    let mut listeners = Vec::with_capacity(bind_addrs.len());
+    for addr in bind_addrs {
+        let server = Server::new(addr, session_ports.clone(), settings.keys.clone())
+            .map_err(|error| Error::other(format!("{}: {}", addr, Error::from(error))))?;
+        info!("Listening on {}", addr);
+
+        let settings = settings.clone();
+        listeners.push(thread::spawn(move || listen(server, settings)));
+    }
+
+    for listener in listeners {
+        let _ = listener.join();
+    }
+
+    Ok(())
+}
+
+// Everything serve loads, without creating the storage root or the host key
+fn check(config: &Config) -> Result<()> {
+    let symlinks = config.get_symlink_policy()?;
+    let root = Path::new(&config.storage.root);
+    match root.exists() {
+        true => {
+            Sandbox::open(&config.storage.root, symlinks)?;
+        }
+        false => println!("Storage root {} will be created", root.display()),
+    }
+    config.get_quotas(root)?;
+    config.load_keys()?;
+    config.get_timeouts()?;
+    config.get_cipher_suites()?;
+    if config.read_host_key()?.is_none() {
+        println!("Host key {} will be generated", config.security.host_key_file);
+    }
+    config.load_users()?;
+    config.load_authorized_keys()?;
+    config.get_access_policy()?;
+    config.load_roles()?;
+    config.get_bind_addrs()?;
+    config.get_session_ports()?;
+
+    println!("Configuration is valid");
+    Ok(())
+}
+
+fn listen(mut server: Server, settings: SessionSettings) {
     loop {
-        let client = server.accept()?;
-        let mut session = Session::new(client, 1, cypher);
-        session.start();
-        break;
+        let client = match server.accept() {
+            Ok(client) => client,
+            Err(error) => {
+                error!("Error while accepting client: {}", Error::from(error));
+                continue;
+            }
+        };
+
+        info!("Session {} opened for {}", client.session_id(), client.peer_addr());
+        let session_id = client.session_id();
+        let settings = settings.clone();
+        thread::spawn(move || {
+            let mut session = Session::new(client, session_id, settings);
+            session.start();
+            info!("Session {} closed", session_id);
+        });
+    }
+}
+
+fn sweep_partials(root: PathBuf, max_age: Duration) {
+    loop {
+        match filesystem::sweep_partials(&root, max_age) {
+            Ok(0) => (),
+            Ok(removed) => info!("Removed {} files of expired partial uploads", removed),
+            Err(error) => warn!("Sweeping partial uploads failed: {}", Error::from(error)),
+        }
+        thread::sleep(max_age.min(PARTIAL_SWEEP_INTERVAL));
+    }
+}
+
+fn hash_password() -> Result<()> {
+    let mut password = String::new();
+    stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err(Error::other("Password is empty"));
     }
 
+    println!("{}", users::hash_password(password)?);
     Ok(())
-}
\ No newline at end of file
+}
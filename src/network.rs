@@ -1,7 +1,25 @@
+use std::collections::{HashMap, VecDeque};
 use std::io::{Error, ErrorKind};
-use std::net::{SocketAddr, UdpSocket};
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::ops::RangeInclusive;
 use std::result::Result;
-// use std::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+use log::{debug, warn};
+use super::datagram::{opens_session, Header};
+use super::keys::KeyRing;
+use super::rate_limit::RateLimiter;
+
+pub const MAX_DATAGRAM_SIZE: usize = 65536;
+const SESSION_SOCKET_POLL: Duration = Duration::from_secs(1);
+const ACCEPT_POLL: Duration = Duration::from_secs(5);
+// Datagrams waiting for a busy session beyond this are dropped, the peer retransmits them
+const SESSION_QUEUE_SIZE: usize = 64;
+const MAX_OPENINGS_PER_ADDR: u32 = 16;
+const OPENING_WINDOW: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 pub enum NetworkError {
@@ -11,63 +29,282 @@ pub enum NetworkError {
     ReceiveFailed,
     SetTimeoutFailed,
     CloneFailed,
+    Disconnected,
+    NoFreePorts,
+    Timeout,
 }
 
 impl From<NetworkError> for Error {
     fn from(error: NetworkError) -> Error {
         match error {
-            NetworkError::BindFailed => Error::new(ErrorKind::Other, "Failed to bind to address"),
-            NetworkError::ConnectionFailed => Error::new(ErrorKind::Other, "Connection failed"),
-            NetworkError::SendFailed => Error::new(ErrorKind::Other, "Send failed"),
-            NetworkError::ReceiveFailed => Error::new(ErrorKind::Other, "Receive failed"),
-            NetworkError::SetTimeoutFailed => Error::new(ErrorKind::Other, "Set timeout failed"),
-            NetworkError::CloneFailed => Error::new(ErrorKind::Other, "Clone failed"),
+            NetworkError::BindFailed => Error::other("Failed to bind to address"),
+            NetworkError::ConnectionFailed => Error::other("Connection failed"),
+            NetworkError::SendFailed => Error::other("Send failed"),
+            NetworkError::ReceiveFailed => Error::other("Receive failed"),
+            NetworkError::SetTimeoutFailed => Error::other("Set timeout failed"),
+            NetworkError::CloneFailed => Error::other("Clone failed"),
+            NetworkError::Disconnected => Error::other("Client disconnected"),
+            NetworkError::NoFreePorts => Error::other("No free session ports"),
+            NetworkError::Timeout => Error::other("Timed out"),
+        }
+    }
+}
+
+struct PortPool {
+    free: VecDeque<u16>,
+}
+
+impl PortPool {
+    fn new(range: RangeInclusive<u16>) -> Self {
+        PortPool { free: range.collect() }
+    }
+
+    // Released ports go to the back so a lingering reader has time to let go of the old socket
+    fn bind(&mut self, ip: IpAddr) -> Result<(UdpSocket, u16), NetworkError> {
+        for _ in 0..self.free.len() {
+            let port = match self.free.pop_front() {
+                Some(port) => port,
+                None => break,
+            };
+
+            match UdpSocket::bind(SocketAddr::new(ip, port)) {
+                Ok(socket) => return Ok((socket, port)),
+                Err(_) => self.free.push_back(port),
+            }
         }
+
+        Err(NetworkError::NoFreePorts)
+    }
+
+    fn release(&mut self, port: u16) {
+        self.free.push_back(port);
     }
 }
 
+struct ClientHandle {
+    addr: SocketAddr,
+    sender: SyncSender<Vec<u8>>,
+}
+
+struct Released {
+    addr: SocketAddr,
+    session_id: u32,
+    port: u16,
+}
+
 pub struct Server {
+    ip: IpAddr,
     socket: UdpSocket,
+    ports: PortPool,
+    keys: KeyRing,
+    // Routed by the session id in the header, the address only has to match the one the session started from
+    sessions: HashMap<u32, ClientHandle>,
+    // Handshakes carry no session id yet, they go to the session their address opened
+    opened_by: HashMap<SocketAddr, u32>,
+    openings: RateLimiter,
+    next_session_id: u32,
+    released_sender: Sender<Released>,
+    released_receiver: Receiver<Released>,
 }
 
 impl Server {
-    pub fn new(port: &str) -> Result<Self, NetworkError> {
-        let addr = "0.0.0.0:".to_string() + port;
+    pub fn new(addr: SocketAddr, session_ports: RangeInclusive<u16>, keys: KeyRing) -> Result<Self, NetworkError> {
+        let ip = addr.ip();
         let socket = UdpSocket::bind(addr).map_err(|_| NetworkError::BindFailed)?;
-        // socket.set_read_timeout(Some(Duration::from_secs(5))).map_err(|_| NetworkError::SetTimeoutFailed)?;
-        Ok(Server { socket })
+        socket.set_read_timeout(Some(ACCEPT_POLL)).map_err(|_| NetworkError::SetTimeoutFailed)?;
+        let (released_sender, released_receiver) = channel();
+        Ok(Server { ip, socket, ports: PortPool::new(session_ports), keys, sessions: HashMap::new(),
+            opened_by: HashMap::new(), openings: RateLimiter::new(MAX_OPENINGS_PER_ADDR, OPENING_WINDOW),
+            next_session_id: 1, released_sender, released_receiver })
     }
 
-    pub fn accept(&self) -> Result<Client, NetworkError> {
-        let mut buf = [0u8; 1];
-        let (_, addr) = self.socket.recv_from(&mut buf).map_err(|_| NetworkError::ReceiveFailed)?;
-        let client = Client::new(self.socket.try_clone().
-            map_err(|_| NetworkError::CloneFailed)?, addr)?;
-        Ok(client)
+    // Routes datagrams of known sessions and returns once an authentic datagram opens a new one
+    pub fn accept(&mut self) -> Result<Client, NetworkError> {
+        loop {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            let received = self.socket.recv_from(&mut buf);
+            self.release_finished();
+            let (size, addr) = match received {
+                Ok(value) => value,
+                Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+                Err(_) => return Err(NetworkError::ReceiveFailed),
+            };
+            buf.truncate(size);
+
+            let header_session_id = match Header::parse(&buf) {
+                Ok(header) => header.get_session_id(),
+                Err(_) => continue,
+            };
+            let session_id = match header_session_id {
+                0 => self.opened_by.get(&addr).copied(),
+                session_id => Some(session_id),
+            };
+            if let Some(handle) = session_id.and_then(|session_id| self.sessions.get(&session_id)) {
+                if handle.addr != addr {
+                    debug!("Dropping datagram for session {} from {}", header_session_id, addr);
+                    continue;
+                }
+                match handle.sender.try_send(buf) {
+                    Ok(()) | Err(TrySendError::Full(_)) => continue,
+                    // The session ended since the last release, a handshake may open a new one
+                    Err(TrySendError::Disconnected(returned)) => {
+                        buf = returned;
+                        self.release_finished();
+                    }
+                }
+            }
+
+            if header_session_id != 0 || !opens_session(&self.keys, &buf) {
+                continue;
+            }
+            if !self.openings.allow(addr.ip()) {
+                warn!("Too many new sessions from {}, dropping datagram", addr.ip());
+                continue;
+            }
+
+            let session_id = match self.allocate_session_id() {
+                Some(session_id) => session_id,
+                None => {
+                    warn!("Session table is full, dropping datagram from {}", addr);
+                    continue;
+                }
+            };
+
+            let (session_socket, port) = match self.ports.bind(self.ip) {
+                Ok(value) => value,
+                Err(error) => {
+                    warn!("Dropping datagram from {}: {}", addr, Error::from(error));
+                    continue;
+                }
+            };
+
+            let client = match self.open_client(addr, session_id, session_socket, port, buf) {
+                Ok(client) => client,
+                Err(error) => {
+                    self.ports.release(port);
+                    return Err(error);
+                }
+            };
+            self.opened_by.insert(addr, session_id);
+
+            return Ok(client);
+        }
     }
 
+    fn open_client(&mut self, addr: SocketAddr, session_id: u32, session_socket: UdpSocket, port: u16,
+                   datagram: Vec<u8>) -> Result<Client, NetworkError> {
+        session_socket.connect(addr).map_err(|_| NetworkError::ConnectionFailed)?;
+        session_socket.set_read_timeout(Some(SESSION_SOCKET_POLL)).map_err(|_| NetworkError::SetTimeoutFailed)?;
+        let reader_socket = session_socket.try_clone().map_err(|_| NetworkError::CloneFailed)?;
+        let socket = self.socket.try_clone().map_err(|_| NetworkError::CloneFailed)?;
+
+        let (sender, receiver) = sync_channel(SESSION_QUEUE_SIZE);
+        sender.send(datagram).map_err(|_| NetworkError::Disconnected)?;
+        let closed = Arc::new(AtomicBool::new(false));
+        spawn_session_reader(reader_socket, sender.clone(), closed.clone());
+        self.sessions.insert(session_id, ClientHandle { addr, sender });
+
+        Ok(Client { socket, session_socket, redirected: false, peer_addr: addr, session_id, port, receiver,
+            closed, released: self.released_sender.clone() })
+    }
+
+    fn allocate_session_id(&mut self) -> Option<u32> {
+        for _ in 0..=self.sessions.len() {
+            let session_id = self.next_session_id;
+            self.next_session_id = self.next_session_id.checked_add(1).unwrap_or(1);
+            if !self.sessions.contains_key(&session_id) {
+                return Some(session_id);
+            }
+        }
+
+        None
+    }
+
+    // Every client reports here exactly once, when it is dropped
+    fn release(&mut self, released: Released) {
+        self.sessions.remove(&released.session_id);
+        if self.opened_by.get(&released.addr) == Some(&released.session_id) {
+            self.opened_by.remove(&released.addr);
+        }
+        self.ports.release(released.port);
+    }
+
+    fn release_finished(&mut self) {
+        while let Ok(released) = self.released_receiver.try_recv() {
+            self.release(released);
+        }
+    }
+}
+
+// Feeds datagrams arriving on the dedicated port into the same queue as the ones routed by the main port
+fn spawn_session_reader(socket: UdpSocket, sender: SyncSender<Vec<u8>>, closed: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        while !closed.load(Ordering::Relaxed) {
+            let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+            let size = match socket.recv(&mut buf) {
+                Ok(size) => size,
+                Err(_) => continue,
+            };
+            buf.truncate(size);
+
+            if sender.send(buf).is_err() {
+                break;
+            }
+        }
+    });
 }
 
 pub struct Client {
     socket: UdpSocket,
+    session_socket: UdpSocket,
+    redirected: bool,
     peer_addr: SocketAddr,
+    session_id: u32,
+    port: u16,
+    receiver: Receiver<Vec<u8>>,
+    closed: Arc<AtomicBool>,
+    released: Sender<Released>,
 }
 
 impl Client {
-    fn new(socket: UdpSocket, peer_addr: SocketAddr) -> Result<Self, NetworkError> {
-        socket.connect(peer_addr).map_err(|_| NetworkError::ConnectionFailed)?;
-        Ok(Client { socket, peer_addr })
+    pub fn send(&mut self, data: &[u8]) -> Result<usize, NetworkError> {
+        if self.redirected {
+            return self.session_socket.send(data).map_err(|_| NetworkError::SendFailed);
+        }
+
+        self.socket.send_to(data, self.peer_addr).map_err(|_| NetworkError::SendFailed)
     }
 
-    pub fn send(&mut self, data: &[u8]) -> Result<usize, NetworkError> {
-        self.socket.send(&data).map_err(|_| NetworkError::SendFailed)
+    pub fn redirect(&mut self) {
+        self.redirected = true;
     }
 
-    pub fn recv(&mut self, buffer: &mut [u8]) -> Result<usize, NetworkError> {
-        self.socket.recv(buffer).map_err(|_| NetworkError::ReceiveFailed)
+    pub fn recv(&mut self, buffer: &mut [u8], timeout: Duration) -> Result<usize, NetworkError> {
+        let datagram = self.receiver.recv_timeout(timeout).map_err(|error| match error {
+            RecvTimeoutError::Timeout => NetworkError::Timeout,
+            RecvTimeoutError::Disconnected => NetworkError::Disconnected,
+        })?;
+        let size = datagram.len().min(buffer.len());
+        buffer[..size].copy_from_slice(&datagram[..size]);
+        Ok(size)
     }
 
     pub fn peer_addr(&self) -> SocketAddr {
         self.peer_addr
     }
-}
\ No newline at end of file
+
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.released.send(Released { addr: self.peer_addr, session_id: self.session_id, port: self.port });
+    }
+}
@@ -0,0 +1,106 @@
+const WORD_BITS: u32 = 64;
+
+// Tracks which chunks arrived, chunk ids start from 1
+#[derive(Clone)]
+pub struct ChunkBitmap {
+    words: Vec<u64>,
+    len: u32,
+    count: u32,
+}
+
+impl ChunkBitmap {
+    pub fn new(len: u32) -> Self {
+        ChunkBitmap { words: vec![0; len.div_ceil(WORD_BITS) as usize], len, count: 0 }
+    }
+
+    pub fn from_words(len: u32, words: Vec<u64>) -> Option<Self> {
+        if words.len() != len.div_ceil(WORD_BITS) as usize {
+            return None;
+        }
+
+        let mut bitmap = ChunkBitmap { words, len, count: 0 };
+        for chunk_id in 1..=len {
+            if bitmap.contains(chunk_id) {
+                bitmap.count += 1;
+            }
+        }
+
+        Some(bitmap)
+    }
+
+    // Returns false if the chunk was already marked or is out of range
+    pub fn set(&mut self, chunk_id: u32) -> bool {
+        if chunk_id == 0 || chunk_id > self.len || self.contains(chunk_id) {
+            return false;
+        }
+
+        let index = chunk_id - 1;
+        self.words[(index / WORD_BITS) as usize] |= 1 << (index % WORD_BITS);
+        self.count += 1;
+        true
+    }
+
+    pub fn contains(&self, chunk_id: u32) -> bool {
+        if chunk_id == 0 || chunk_id > self.len {
+            return false;
+        }
+
+        let index = chunk_id - 1;
+        self.words[(index / WORD_BITS) as usize] & (1 << (index % WORD_BITS)) != 0
+    }
+
+    pub fn is_complete(&self) -> bool { self.count == self.len }
+
+    pub fn missing(&self, limit: usize) -> Vec<u32> {
+        (1..=self.len).filter(|chunk_id| !self.contains(*chunk_id)).take(limit).collect()
+    }
+
+    pub fn get_words(&self) -> &[u64] { &self.words }
+    pub fn get_len(&self) -> u32 { self.len }
+    pub fn get_count(&self) -> u32 { self.count }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_are_marked_once_and_in_range() {
+        let mut bitmap = ChunkBitmap::new(70);
+        assert!(!bitmap.set(0));
+        assert!(!bitmap.set(71));
+        assert!(bitmap.set(1));
+        assert!(bitmap.set(65));
+        assert!(!bitmap.set(65));
+
+        assert!(bitmap.contains(65));
+        assert!(!bitmap.contains(64));
+        assert_eq!(bitmap.get_count(), 2);
+        assert_eq!(bitmap.missing(3), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn bitmap_completes_when_every_chunk_arrived() {
+        let mut bitmap = ChunkBitmap::new(3);
+        for chunk_id in [3, 1, 2] {
+            assert!(!bitmap.is_complete());
+            bitmap.set(chunk_id);
+        }
+        assert!(bitmap.is_complete());
+        assert!(bitmap.missing(10).is_empty());
+        assert!(ChunkBitmap::new(0).is_complete());
+    }
+
+    #[test]
+    fn words_round_trip() {
+        let mut bitmap = ChunkBitmap::new(100);
+        for chunk_id in [1, 64, 65, 100] {
+            bitmap.set(chunk_id);
+        }
+
+        let restored = ChunkBitmap::from_words(100, bitmap.get_words().to_vec()).unwrap();
+        assert_eq!(restored.get_count(), 4);
+        assert!(restored.contains(64) && restored.contains(100) && !restored.contains(99));
+        assert!(ChunkBitmap::from_words(100, vec![0]).is_none());
+    }
+}
@@ -1,12 +1,25 @@
+use super::enums::{FILE_CHUNK_SIZE, LIST_PAGE_MAX_ENTRIES};
+use super::bitmap::ChunkBitmap;
+use super::listing::ListEntry;
+use super::limits::Limits;
+use super::permissions::{Permission, DEFAULT_PERMISSIONS};
+use super::quota::QuotaEntry;
+
 struct SessionMeta {
-    session_id: u8,
+    session_id: u32,
+    redirect_port: u16,
+    secured: bool,
+    auth_required: bool,
+    authenticated: bool,
+    permissions: Vec<Permission>,
     started: bool,
     current_method: u8,
 }
 
 impl SessionMeta {
-    fn new(session_id: u8) -> SessionMeta {
-        SessionMeta { session_id, started: false, current_method: 0 }
+    fn new(session_id: u32) -> SessionMeta {
+        SessionMeta { session_id, redirect_port: 0, secured: false, auth_required: false, authenticated: false,
+            permissions: DEFAULT_PERMISSIONS.to_vec(), started: false, current_method: 0 }
     }
 
     fn reset(&mut self) {
@@ -23,12 +36,24 @@ struct FileState {
     chunk_count: u32,
     current_chunk_id: u32,
     data_chunk: Vec<u8>,
+
+    // Byte range of a download, chunk ids count from the range start
+    range_offset: u64,
+    range_length: Option<u64>,
+
+    window_size: u32,
+    acked_chunk_id: u32,
+    sent_chunk_id: u32,
+    pending_chunks: Vec<u32>,
+
+    received_chunks: ChunkBitmap,
 }
 
 impl FileState {
     fn new() -> FileState {
         FileState { is_open: false, path: String::new(), size: 0, chunk_count: 0, current_chunk_id: 0,
-            data_chunk: Vec::new() }
+            data_chunk: Vec::new(), range_offset: 0, range_length: None, window_size: 0, acked_chunk_id: 0,
+            sent_chunk_id: 0, pending_chunks: Vec::new(), received_chunks: ChunkBitmap::new(0) }
     }
 
     fn reset(&mut self) {
@@ -38,49 +63,344 @@ impl FileState {
         self.chunk_count = 0;
         self.current_chunk_id = 0;
         self.data_chunk = Vec::new();
+        self.range_offset = 0;
+        self.range_length = None;
+        self.window_size = 0;
+        self.acked_chunk_id = 0;
+        self.sent_chunk_id = 0;
+        self.pending_chunks = Vec::new();
+        self.received_chunks = ChunkBitmap::new(0);
+    }
+
+    // Everything reported missing plus whatever fits into the window after the acknowledged chunk
+    fn queue_window(&mut self, missing: Vec<u32>) {
+        self.pending_chunks = missing;
+        let window_end = self.acked_chunk_id.saturating_add(self.window_size).min(self.chunk_count);
+        while self.sent_chunk_id < window_end {
+            self.sent_chunk_id += 1;
+            self.pending_chunks.push(self.sent_chunk_id);
+        }
+    }
+}
+
+struct ListState {
+    entries: Vec<Vec<u8>>,
+    pages: Vec<(usize, usize)>,
+}
+
+impl ListState {
+    fn new() -> ListState {
+        ListState { entries: Vec::new(), pages: Vec::new() }
+    }
+
+    fn reset(&mut self) {
+        self.entries = Vec::new();
+        self.pages = Vec::new();
+    }
+
+    fn set_entries(&mut self, entries: Vec<ListEntry>) {
+        self.reset();
+
+        let mut page_start = 0;
+        let mut page_size = 0;
+        for entry in entries {
+            let bytes = entry.get_bytes();
+            let field_size = 3 + bytes.len() + 1;
+            let page_len = self.entries.len() - page_start;
+            let page_full = page_len == LIST_PAGE_MAX_ENTRIES || page_size + field_size > FILE_CHUNK_SIZE as usize;
+            if page_len > 0 && page_full {
+                self.pages.push((page_start, self.entries.len()));
+                page_start = self.entries.len();
+                page_size = 0;
+            }
+
+            page_size += field_size;
+            self.entries.push(bytes);
+        }
+
+        if page_start < self.entries.len() {
+            self.pages.push((page_start, self.entries.len()));
+        }
+    }
+
+    fn get_page(&self, page_id: u32) -> &[Vec<u8>] {
+        match self.pages.get((page_id as usize).wrapping_sub(1)) {
+            Some((start, end)) => &self.entries[*start..*end],
+            None => &[],
+        }
+    }
+}
+
+struct HandShakeState {
+    peer_public_key: Vec<u8>,
+    public_key: Vec<u8>,
+    offered_suites: Vec<String>,
+    cipher_suite: String,
+    host_key: Vec<u8>,
+    host_signature: Vec<u8>,
+}
+
+impl HandShakeState {
+    fn new() -> HandShakeState {
+        HandShakeState { peer_public_key: Vec::new(), public_key: Vec::new(), offered_suites: Vec::new(),
+            cipher_suite: String::new(), host_key: Vec::new(), host_signature: Vec::new() }
+    }
+
+    fn reset(&mut self) {
+        self.peer_public_key = Vec::new();
+        self.public_key = Vec::new();
+        self.offered_suites = Vec::new();
+        self.cipher_suite = String::new();
+        self.host_key = Vec::new();
+        self.host_signature = Vec::new();
+    }
+}
+
+// Credentials only live here between the two passes of an auth request
+struct AuthState {
+    username: String,
+    password: String,
+    client_key: Vec<u8>,
+    signature: Vec<u8>,
+    verified: bool,
+}
+
+impl AuthState {
+    fn new() -> AuthState {
+        AuthState { username: String::new(), password: String::new(), client_key: Vec::new(), signature: Vec::new(),
+            verified: false }
+    }
+
+    fn reset(&mut self) {
+        self.username = String::new();
+        self.password = String::new();
+        self.client_key = Vec::new();
+        self.signature = Vec::new();
+        self.verified = false;
+    }
+}
+
+struct QueryState {
+    user: String,
+    permissions: Vec<Permission>,
+    usage: Vec<QuotaEntry>,
+}
+
+impl QueryState {
+    fn new() -> QueryState {
+        QueryState { user: String::new(), permissions: Vec::new(), usage: Vec::new() }
+    }
+
+    fn reset(&mut self) {
+        self.user = String::new();
+        self.permissions = Vec::new();
+        self.usage = Vec::new();
     }
 }
 
 pub struct ProtocolContext {
     meta: SessionMeta,
     file: FileState,
+    list: ListState,
+    handshake: HandShakeState,
+    auth: AuthState,
+    query: QueryState,
+    limits: Limits,
     response: Vec<u8>,
     err_msg: String,
 }
 
 impl ProtocolContext {
-    pub fn new(session_id: u8) -> ProtocolContext {
+    pub fn new(session_id: u32) -> ProtocolContext {
         ProtocolContext { meta: SessionMeta::new(session_id), file: FileState::new(),
-            response: Vec::new(), err_msg: String::new() }
+            list: ListState::new(), handshake: HandShakeState::new(), auth: AuthState::new(), query: QueryState::new(),
+            limits: Limits::new(), response: Vec::new(), err_msg: String::new() }
     }
 
     pub fn reset(&mut self) {
         self.meta.reset();
         self.file.reset();
+        self.list.reset();
+        self.handshake.reset();
+        self.auth.reset();
+        self.query.reset();
         self.response.clear();
         self.err_msg.clear();
     }
 
-    pub fn get_session_id(&self) -> u8 { self.meta.session_id }
+    pub fn get_session_id(&self) -> u32 { self.meta.session_id }
+    pub fn get_redirect_port(&self) -> u16 { self.meta.redirect_port }
+    pub fn get_secured(&self) -> bool { self.meta.secured }
     pub fn get_started(&self) -> bool { self.meta.started }
     pub fn get_current_method(&self) -> u8 { self.meta.current_method }
     pub fn get_response(&self) -> &[u8] { &self.response }
     pub fn get_err_msg(&self) -> &str { &self.err_msg }
+    pub fn get_limits(&self) -> Limits { self.limits }
     pub fn get_file_open(&self) -> bool { self.file.is_open }
     pub fn get_file_path(&self) -> &str { &self.file.path }
     pub fn get_file_size(&self) -> u64 { self.file.size }
     pub fn get_chunk_count(&self) -> u32 { self.file.chunk_count }
     pub fn get_current_chunk_id(&self) -> u32 { self.file.current_chunk_id }
     pub fn get_data_chunk(&self) -> &[u8] { &self.file.data_chunk }
+    pub fn get_range_offset(&self) -> u64 { self.file.range_offset }
+    pub fn get_range_length(&self) -> Option<u64> { self.file.range_length }
+    pub fn get_window_size(&self) -> u32 { self.file.window_size }
+    pub fn get_acked_chunk_id(&self) -> u32 { self.file.acked_chunk_id }
+    pub fn get_sent_chunk_id(&self) -> u32 { self.file.sent_chunk_id }
+    pub fn get_pending_chunks(&self) -> &[u32] { &self.file.pending_chunks }
+    pub fn get_received_chunks(&self) -> &ChunkBitmap { &self.file.received_chunks }
+    pub fn get_peer_public_key(&self) -> &[u8] { &self.handshake.peer_public_key }
+    pub fn get_public_key(&self) -> &[u8] { &self.handshake.public_key }
+    pub fn get_auth_required(&self) -> bool { self.meta.auth_required }
+    pub fn get_authenticated(&self) -> bool { self.meta.authenticated }
+    pub fn get_username(&self) -> &str { &self.auth.username }
+    pub fn get_password(&self) -> &str { &self.auth.password }
+    pub fn get_client_key(&self) -> &[u8] { &self.auth.client_key }
+    pub fn get_signature(&self) -> &[u8] { &self.auth.signature }
+    pub fn get_auth_verified(&self) -> bool { self.auth.verified }
+    pub fn has_permission(&self, permission: Permission) -> bool { self.meta.permissions.contains(&permission) }
+    pub fn get_query_user(&self) -> &str { &self.query.user }
+    pub fn get_effective_permissions(&self) -> &[Permission] { &self.query.permissions }
+    pub fn get_usage(&self) -> &[QuotaEntry] { &self.query.usage }
+    pub fn get_offered_suites(&self) -> &[String] { &self.handshake.offered_suites }
+    pub fn get_cipher_suite(&self) -> &str { &self.handshake.cipher_suite }
+    pub fn get_host_key(&self) -> &[u8] { &self.handshake.host_key }
+    pub fn get_host_signature(&self) -> &[u8] { &self.handshake.host_signature }
+    pub fn get_list_page(&self) -> &[Vec<u8>] { self.list.get_page(self.file.current_chunk_id) }
 
+    pub fn set_redirect_port(&mut self, port: u16) { self.meta.redirect_port = port; }
+    pub fn set_secured(&mut self, secured: bool) { self.meta.secured = secured; }
     pub fn set_started(&mut self, started: bool) { self.meta.started = started; }
     pub fn set_current_method(&mut self, method: u8) { self.meta.current_method = method; }
     pub fn set_response(&mut self, response: Vec<u8>) { self.response = response; }
-    pub fn set_err_msg(&mut self, err_msg: String) { self.err_msg = err_msg; }
+    // Error messages may carry client input, so they are cut to the configured length
+    pub fn set_err_msg(&mut self, mut err_msg: String) {
+        let mut length = err_msg.len().min(self.limits.get_max_err_msg_length());
+        while !err_msg.is_char_boundary(length) {
+            length -= 1;
+        }
+        err_msg.truncate(length);
+        self.err_msg = err_msg;
+    }
+
+    pub fn set_limits(&mut self, limits: Limits) { self.limits = limits; }
     pub fn set_file_open(&mut self, open: bool) { self.file.is_open = open; }
     pub fn set_file_path(&mut self, path: String) { self.file.path = path; }
     pub fn set_file_size(&mut self, file_size: u64) { self.file.size = file_size; }
     pub fn set_chunk_count(&mut self, chunk_count: u32) { self.file.chunk_count = chunk_count; }
     pub fn increment_current_chunk_id(&mut self) { self.file.current_chunk_id += 1; }
+    pub fn set_current_chunk_id(&mut self, chunk_id: u32) { self.file.current_chunk_id = chunk_id; }
     pub fn set_data_chunk(&mut self, data_chunk: Vec<u8>) { self.file.data_chunk = data_chunk; }
-}
\ No newline at end of file
+    pub fn set_range_offset(&mut self, offset: u64) { self.file.range_offset = offset; }
+    pub fn set_range_length(&mut self, length: Option<u64>) { self.file.range_length = length; }
+    pub fn set_window_size(&mut self, window_size: u32) { self.file.window_size = window_size; }
+    pub fn set_acked_chunk_id(&mut self, chunk_id: u32) { self.file.acked_chunk_id = chunk_id; }
+    pub fn queue_window(&mut self, missing: Vec<u32>) { self.file.queue_window(missing); }
+    pub fn init_received_chunks(&mut self) { self.file.received_chunks = ChunkBitmap::new(self.file.chunk_count); }
+    pub fn set_received_chunks(&mut self, received_chunks: ChunkBitmap) { self.file.received_chunks = received_chunks; }
+    pub fn set_chunk_received(&mut self, chunk_id: u32) -> bool { self.file.received_chunks.set(chunk_id) }
+    pub fn set_peer_public_key(&mut self, public_key: Vec<u8>) { self.handshake.peer_public_key = public_key; }
+    pub fn set_public_key(&mut self, public_key: Vec<u8>) { self.handshake.public_key = public_key; }
+    pub fn set_auth_required(&mut self, auth_required: bool) { self.meta.auth_required = auth_required; }
+    pub fn set_credentials(&mut self, username: String, password: String) {
+        self.auth.username = username;
+        self.auth.password = password;
+    }
+    pub fn set_key_proof(&mut self, client_key: Vec<u8>, signature: Vec<u8>) {
+        self.auth.client_key = client_key;
+        self.auth.signature = signature;
+    }
+    pub fn set_auth_verified(&mut self) {
+        self.auth.password = String::new();
+        self.auth.verified = true;
+        self.meta.authenticated = true;
+    }
+    pub fn set_permissions(&mut self, permissions: Vec<Permission>) { self.meta.permissions = permissions; }
+    pub fn set_query_user(&mut self, user: String) { self.query.user = user; }
+    pub fn set_effective_permissions(&mut self, permissions: Vec<Permission>) { self.query.permissions = permissions; }
+    pub fn set_usage(&mut self, usage: Vec<QuotaEntry>) { self.query.usage = usage; }
+    pub fn set_offered_suites(&mut self, suites: Vec<String>) { self.handshake.offered_suites = suites; }
+    pub fn set_cipher_suite(&mut self, suite: String) { self.handshake.cipher_suite = suite; }
+    pub fn set_host_signature(&mut self, host_key: Vec<u8>, signature: Vec<u8>) {
+        self.handshake.host_key = host_key;
+        self.handshake.host_signature = signature;
+    }
+
+    pub fn set_list_entries(&mut self, entries: Vec<ListEntry>) {
+        self.list.set_entries(entries);
+        self.file.chunk_count = self.list.pages.len() as u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::enums::EntryKind;
+    use super::*;
+
+    fn entries(count: usize, name: &str) -> Vec<ListEntry> {
+        (0..count).map(|_| ListEntry::new(EntryKind::File, String::from(name), 0, 0)).collect()
+    }
+
+    #[test]
+    fn pages_follow_the_cursor_up_to_the_last() {
+        let mut context = ProtocolContext::new(1);
+        context.set_list_entries(entries(2 * LIST_PAGE_MAX_ENTRIES + 1, "a"));
+        assert_eq!(context.get_chunk_count(), 3);
+        assert!(context.get_list_page().is_empty());
+
+        context.increment_current_chunk_id();
+        assert_eq!(context.get_list_page().len(), LIST_PAGE_MAX_ENTRIES);
+        context.increment_current_chunk_id();
+        assert_eq!(context.get_list_page().len(), LIST_PAGE_MAX_ENTRIES);
+        context.increment_current_chunk_id();
+        assert_eq!(context.get_list_page().len(), 1);
+        context.increment_current_chunk_id();
+        assert!(context.get_list_page().is_empty());
+    }
+
+    #[test]
+    fn pages_fit_into_a_chunk() {
+        let mut context = ProtocolContext::new(1);
+        context.set_list_entries(entries(100, &"n".repeat(1000)));
+        assert!(context.get_chunk_count() > 1);
+
+        let mut count = 0;
+        for _ in 0..context.get_chunk_count() {
+            context.increment_current_chunk_id();
+            let page = context.get_list_page();
+            assert!(page.iter().map(|entry| 3 + entry.len() + 1).sum::<usize>() <= FILE_CHUNK_SIZE as usize);
+            count += page.len();
+        }
+        assert_eq!(count, 100);
+    }
+
+    #[test]
+    fn empty_listings_have_no_pages() {
+        let mut context = ProtocolContext::new(1);
+        context.set_list_entries(Vec::new());
+        assert_eq!(context.get_chunk_count(), 0);
+
+        context.increment_current_chunk_id();
+        assert!(context.get_list_page().is_empty());
+    }
+
+    #[test]
+    fn window_queues_chunks_after_the_acknowledged_one() {
+        let mut context = ProtocolContext::new(1);
+        context.set_chunk_count(10);
+        context.set_window_size(4);
+        context.queue_window(Vec::new());
+        assert_eq!(context.get_pending_chunks(), &[1, 2, 3, 4]);
+
+        context.set_acked_chunk_id(2);
+        context.queue_window(vec![4]);
+        assert_eq!(context.get_pending_chunks(), &[4, 5, 6]);
+
+        context.set_acked_chunk_id(9);
+        context.queue_window(Vec::new());
+        assert_eq!(context.get_pending_chunks(), &[7, 8, 9, 10]);
+        context.set_acked_chunk_id(10);
+        context.queue_window(Vec::new());
+        assert!(context.get_pending_chunks().is_empty());
+    }
+}
@@ -1,5 +1,12 @@
 pub const FILE_CHUNK_SIZE: u16 = 64512;
 pub const EOF: u8 = 0x00;
+pub const LIST_PAGE_MAX_ENTRIES: usize = 250;
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const CLIENT_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+pub const HOST_KEY_SIZE: usize = 32;
+pub const MAX_WINDOW_SIZE: u32 = 32;
+pub const MAX_MISSING_REPORTED: usize = 1024;
 
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -10,6 +17,10 @@ pub enum PacketMethod {
     Upload = 0x03,
     Close = 0x04,
     List = 0x05,
+    Auth = 0x06,
+    Delete = 0x07,
+    Permissions = 0x08,
+    Usage = 0x09,
 }
 
 impl TryFrom<u8> for PacketMethod {
@@ -23,6 +34,10 @@ impl TryFrom<u8> for PacketMethod {
             0x03 => Ok(PacketMethod::Upload),
             0x04 => Ok(PacketMethod::Close),
             0x05 => Ok(PacketMethod::List),
+            0x06 => Ok(PacketMethod::Auth),
+            0x07 => Ok(PacketMethod::Delete),
+            0x08 => Ok(PacketMethod::Permissions),
+            0x09 => Ok(PacketMethod::Usage),
             _ => Err(()),
         }
     }
@@ -41,6 +56,22 @@ pub enum FieldType {
     Status = 0x17,
     FileSize = 0x18,
     ErrorMsg = 0x19,
+    Entry = 0x1A,
+    PublicKey = 0x1B,
+    Port = 0x1C,
+    WindowSize = 0x1D,
+    Missing = 0x1E,
+    Offset = 0x1F,
+    Length = 0x20,
+    CipherSuites = 0x21,
+    CipherSuite = 0x22,
+    Username = 0x23,
+    Password = 0x24,
+    ClientKey = 0x25,
+    Signature = 0x26,
+    HostKey = 0x27,
+    Permissions = 0x28,
+    Quota = 0x29,
 }
 
 impl TryFrom<u8> for FieldType {
@@ -58,6 +89,22 @@ impl TryFrom<u8> for FieldType {
             0x17 => Ok(FieldType::Status),
             0x18 => Ok(FieldType::FileSize),
             0x19 => Ok(FieldType::ErrorMsg),
+            0x1A => Ok(FieldType::Entry),
+            0x1B => Ok(FieldType::PublicKey),
+            0x1C => Ok(FieldType::Port),
+            0x1D => Ok(FieldType::WindowSize),
+            0x1E => Ok(FieldType::Missing),
+            0x1F => Ok(FieldType::Offset),
+            0x20 => Ok(FieldType::Length),
+            0x21 => Ok(FieldType::CipherSuites),
+            0x22 => Ok(FieldType::CipherSuite),
+            0x23 => Ok(FieldType::Username),
+            0x24 => Ok(FieldType::Password),
+            0x25 => Ok(FieldType::ClientKey),
+            0x26 => Ok(FieldType::Signature),
+            0x27 => Ok(FieldType::HostKey),
+            0x28 => Ok(FieldType::Permissions),
+            0x29 => Ok(FieldType::Quota),
             _ => Err(()),
         }
     }
@@ -72,6 +119,10 @@ pub enum FieldStatus {
     Error = 0x23,
     Ok = 0x24,
     Retry = 0x25,
+    Missing = 0x26,
+    Denied = 0x27,
+    QuotaExceeded = 0x28,
+    InsufficientStorage = 0x29,
 }
 
 #[repr(u8)]
@@ -84,6 +135,7 @@ pub enum FieldCommand {
     Cancel = 0x34,
     Send = 0x35,
     Continue = 0x36,
+    Ack = 0x37,
 }
 
 impl TryFrom<u8> for FieldCommand {
@@ -98,11 +150,28 @@ impl TryFrom<u8> for FieldCommand {
             0x34 => Ok(FieldCommand::Cancel),
             0x35 => Ok(FieldCommand::Send),
             0x36 => Ok(FieldCommand::Continue),
+            0x37 => Ok(FieldCommand::Ack),
             _ => Err(()),
         }
     }
 }
 
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File = b'f',
+    Directory = b'd',
+    Symlink = b'l',
+    Other = b'o',
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaScope {
+    User = b'u',
+    Directory = b'd',
+}
+
 pub enum NextAction {
     None,
     Terminate,
@@ -110,6 +179,11 @@ pub enum NextAction {
     WriteData,
     End,
     Cancel,
+    HandShake,
+    Resend,
+    SendWindow,
+    Done,
+    Denied,
 }
 
 pub enum Action {
@@ -117,4 +191,11 @@ pub enum Action {
     SendError,
     RequestFileInfoRead,
     RequestFileInfoWrite,
+    RequestFileInfoResume,
+    RequestListing,
+    RequestHandShake,
+    RequestAuth,
+    RequestDelete,
+    RequestPermissions,
+    RequestUsage,
 }
\ No newline at end of file
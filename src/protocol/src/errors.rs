@@ -1,4 +1,4 @@
-use std::io::{Error, ErrorKind};
+use std::io::Error;
 
 #[derive(Debug)]
 pub enum ParseError {
@@ -9,18 +9,22 @@ pub enum ParseError {
     NotValidMethod,
     NotValidFieldType,
     DuplicateFieldFound,
+    TooManyFields,
+    FieldTooLong,
 }
 
 impl From<ParseError> for Error {
     fn from(error: ParseError) -> Error {
         match error {
-            ParseError::NotValidHeaderLength => Error::new(ErrorKind::Other, "Invalid header length"),
-            ParseError::NotValidFieldLength => Error::new(ErrorKind::Other, "Invalid field length"),
-            ParseError::NotValidFieldDataLength => Error::new(ErrorKind::Other, "Invalid field data length"),
-            ParseError::NotValidFieldsCount => Error::new(ErrorKind::Other, "Invalid fields count"),
-            ParseError::NotValidMethod => Error::new(ErrorKind::Other, "Invalid method"),
-            ParseError::NotValidFieldType => Error::new(ErrorKind::Other, "Invalid field type"),
-            ParseError::DuplicateFieldFound => Error::new(ErrorKind::Other, "Duplicate field found"),
+            ParseError::NotValidHeaderLength => Error::other("Invalid header length"),
+            ParseError::NotValidFieldLength => Error::other("Invalid field length"),
+            ParseError::NotValidFieldDataLength => Error::other("Invalid field data length"),
+            ParseError::NotValidFieldsCount => Error::other("Invalid fields count"),
+            ParseError::NotValidMethod => Error::other("Invalid method"),
+            ParseError::NotValidFieldType => Error::other("Invalid field type"),
+            ParseError::DuplicateFieldFound => Error::other("Duplicate field found"),
+            ParseError::TooManyFields => Error::other("Too many fields"),
+            ParseError::FieldTooLong => Error::other("Field data is too long"),
         }
     }
 }
@@ -35,9 +39,26 @@ pub enum UtilError {
 impl From<UtilError> for Error {
     fn from(error: UtilError) -> Error {
         match error {
-            UtilError::ASCIIParseError => Error::new(ErrorKind::Other, "Not printable or Non-ASCII characters"),
-            UtilError::NumberParseError => Error::new(ErrorKind::Other, "Not number symbol"),
-            UtilError::UIntOverflow => Error::new(ErrorKind::Other, "Unsigned integer overflow"),
+            UtilError::ASCIIParseError => Error::other("Not printable or Non-ASCII characters"),
+            UtilError::NumberParseError => Error::other("Not number symbol"),
+            UtilError::UIntOverflow => Error::other("Unsigned integer overflow"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LimitError {
+    PathTooLong,
+    PathTooDeep,
+    FileTooLarge,
+}
+
+impl From<LimitError> for Error {
+    fn from(error: LimitError) -> Error {
+        match error {
+            LimitError::PathTooLong => Error::other("Path is too long"),
+            LimitError::PathTooDeep => Error::other("Path is too deep"),
+            LimitError::FileTooLarge => Error::other("File size exceeds the upload limit"),
         }
     }
 }
\ No newline at end of file
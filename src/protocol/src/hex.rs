@@ -0,0 +1,45 @@
+pub fn encode_hex(bytes: &[u8]) -> String {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    let mut result = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        result.push(DIGITS[(byte >> 4) as usize] as char);
+        result.push(DIGITS[(byte & 0x0f) as usize] as char);
+    }
+
+    result
+}
+
+fn decode_digit(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|value| value as u8)
+}
+
+// Two ASCII hex digits per byte, in either case and without signs or separators
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let digits = hex.as_bytes();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+
+    digits.chunks(2).map(|pair| Some(decode_digit(pair[0])? << 4 | decode_digit(pair[1])?)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_round_trips() {
+        let bytes = [0x00, 0x1f, 0xa0, 0xff];
+        assert_eq!(encode_hex(&bytes), "001fa0ff");
+        assert_eq!(decode_hex("001fa0ff").unwrap(), bytes);
+        assert_eq!(decode_hex("001FA0FF").unwrap(), bytes);
+        assert_eq!(decode_hex("").unwrap(), []);
+    }
+
+    #[test]
+    fn only_hex_digit_pairs_decode() {
+        for hex in ["0", "+1", "-1", "0x", " 1", "1 ", "zz", "é"] {
+            assert!(decode_hex(hex).is_none(), "{:?}", hex);
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use ed25519_dalek::{Signature, VerifyingKey};
+use super::enums::{HOST_KEY_SIZE, SIGNATURE_SIZE};
+use super::hex::{decode_hex, encode_hex};
+
+// Prepended to the handshake transcript before the server signs it
+const HOST_SIGNATURE_CONTEXT: &[u8] = b"fileserver host key";
+
+#[derive(Debug)]
+pub enum KnownHostsError {
+    ReadFailed,
+    WriteFailed,
+    InvalidLine(usize),
+    InvalidHostKey,
+    BadSignature,
+    UnknownHost(String),
+    HostKeyChanged(String),
+}
+
+impl From<KnownHostsError> for Error {
+    fn from(error: KnownHostsError) -> Error {
+        match error {
+            KnownHostsError::ReadFailed => Error::other("Known hosts read failed"),
+            KnownHostsError::WriteFailed => Error::other("Known hosts write failed"),
+            KnownHostsError::InvalidLine(line) => Error::other(format!("Known hosts line {} should be host key", line)),
+            KnownHostsError::InvalidHostKey => Error::other("Invalid host key"),
+            KnownHostsError::BadSignature => Error::other("Host signature is invalid"),
+            KnownHostsError::UnknownHost(host) => Error::other(format!("Host {} is not known", host)),
+            KnownHostsError::HostKeyChanged(host) => {
+                Error::other(format!("Host key of {} changed, the server may be impersonated", host))
+            }
+        }
+    }
+}
+
+pub fn host_signature_message(transcript: &[u8]) -> Vec<u8> {
+    [HOST_SIGNATURE_CONTEXT, transcript].concat()
+}
+
+// Every handshake has to carry a valid signature, whether the host is known or not
+pub fn verify_host_signature(host_key: &[u8], signature: &[u8], transcript: &[u8]) -> Result<(), KnownHostsError> {
+    let host_key = <[u8; HOST_KEY_SIZE]>::try_from(host_key).map_err(|_| KnownHostsError::InvalidHostKey)?;
+    let host_key = VerifyingKey::from_bytes(&host_key).map_err(|_| KnownHostsError::InvalidHostKey)?;
+    let signature = <[u8; SIGNATURE_SIZE]>::try_from(signature).map_err(|_| KnownHostsError::BadSignature)?;
+    host_key.verify_strict(&host_signature_message(transcript), &Signature::from_bytes(&signature))
+        .map_err(|_| KnownHostsError::BadSignature)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostKeyPolicy {
+    // Unknown hosts are remembered on first contact
+    TrustOnFirstUse,
+    // Only hosts already in the file are accepted
+    Strict,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostTrust {
+    Known,
+    Added,
+}
+
+// Host keys clients have seen, one "host hex-key" line each
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: Vec<(String, Vec<u8>)>,
+}
+
+impl KnownHosts {
+    pub fn load(path: &Path) -> Result<Self, KnownHostsError> {
+        let hosts_str = match fs::read_to_string(path) {
+            Ok(hosts_str) => hosts_str,
+            Err(error) if error.kind() == ErrorKind::NotFound => String::new(),
+            Err(_) => return Err(KnownHostsError::ReadFailed),
+        };
+
+        let mut hosts = Vec::new();
+        for (index, line) in hosts_str.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (host, key) = line.split_once(' ').ok_or(KnownHostsError::InvalidLine(index + 1))?;
+            let key = decode_hex(key.trim()).filter(|key| key.len() == HOST_KEY_SIZE)
+                .ok_or(KnownHostsError::InvalidLine(index + 1))?;
+            hosts.push((host.to_string(), key));
+        }
+
+        Ok(KnownHosts { path: path.to_path_buf(), hosts })
+    }
+
+    pub fn get(&self, host: &str) -> Option<&[u8]> {
+        self.hosts.iter().find(|(known, _)| known == host).map(|(_, key)| key.as_slice())
+    }
+
+    pub fn verify(&mut self, host: &str, host_key: &[u8], signature: &[u8], transcript: &[u8],
+                  policy: HostKeyPolicy) -> Result<HostTrust, KnownHostsError> {
+        verify_host_signature(host_key, signature, transcript)?;
+        match self.get(host) {
+            Some(known_key) if known_key == host_key => Ok(HostTrust::Known),
+            Some(_) => Err(KnownHostsError::HostKeyChanged(host.to_string())),
+            None if policy == HostKeyPolicy::Strict => Err(KnownHostsError::UnknownHost(host.to_string())),
+            None => {
+                self.hosts.push((host.to_string(), host_key.to_vec()));
+                self.save()?;
+                Ok(HostTrust::Added)
+            }
+        }
+    }
+
+    fn save(&self) -> Result<(), KnownHostsError> {
+        let hosts_str: String = self.hosts.iter()
+            .map(|(host, key)| format!("{} {}\n", host, encode_hex(key)))
+            .collect();
+        fs::write(&self.path, hosts_str).map_err(|_| KnownHostsError::WriteFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+    use ed25519_dalek::{Signer, SigningKey};
+    use super::*;
+
+    const HOST: &str = "files.example:7000";
+    const TRANSCRIPT: [u8; 32] = [3; 32];
+
+    fn hosts_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("protocol-known-hosts-{}-{}", name, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn sign(host_key: &SigningKey, transcript: &[u8]) -> Vec<u8> {
+        host_key.sign(&host_signature_message(transcript)).to_bytes().to_vec()
+    }
+
+    fn verify(hosts: &mut KnownHosts, host_key: &SigningKey, policy: HostKeyPolicy)
+        -> Result<HostTrust, KnownHostsError> {
+        let signature = sign(host_key, &TRANSCRIPT);
+        hosts.verify(HOST, host_key.verifying_key().as_bytes(), &signature, &TRANSCRIPT, policy)
+    }
+
+    #[test]
+    fn first_contact_is_recorded() {
+        let path = hosts_path("first-contact");
+        let host_key = SigningKey::from_bytes(&[1; 32]);
+        let mut hosts = KnownHosts::load(&path).unwrap();
+
+        assert_eq!(verify(&mut hosts, &host_key, HostKeyPolicy::TrustOnFirstUse).unwrap(), HostTrust::Added);
+        let mut hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(hosts.get(HOST), Some(&host_key.verifying_key().as_bytes()[..]));
+        assert_eq!(verify(&mut hosts, &host_key, HostKeyPolicy::Strict).unwrap(), HostTrust::Known);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn changed_host_keys_are_rejected() {
+        let path = hosts_path("changed");
+        let host_key = SigningKey::from_bytes(&[1; 32]);
+        let mut hosts = KnownHosts::load(&path).unwrap();
+        verify(&mut hosts, &host_key, HostKeyPolicy::TrustOnFirstUse).unwrap();
+
+        let impostor = SigningKey::from_bytes(&[2; 32]);
+        assert!(matches!(verify(&mut hosts, &impostor, HostKeyPolicy::TrustOnFirstUse),
+                         Err(KnownHostsError::HostKeyChanged(_))));
+        let hosts = KnownHosts::load(&path).unwrap();
+        assert_eq!(hosts.get(HOST), Some(&host_key.verifying_key().as_bytes()[..]));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn strict_mode_rejects_unknown_hosts() {
+        let path = hosts_path("strict");
+        let mut hosts = KnownHosts::load(&path).unwrap();
+
+        assert!(matches!(verify(&mut hosts, &SigningKey::from_bytes(&[1; 32]), HostKeyPolicy::Strict),
+                         Err(KnownHostsError::UnknownHost(_))));
+        assert!(hosts.get(HOST).is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn bad_signatures_are_not_recorded() {
+        let path = hosts_path("signature");
+        let host_key = SigningKey::from_bytes(&[1; 32]);
+        let mut hosts = KnownHosts::load(&path).unwrap();
+
+        let signature = sign(&host_key, &[4; 32]);
+        assert!(matches!(hosts.verify(HOST, host_key.verifying_key().as_bytes(), &signature, &TRANSCRIPT,
+                                      HostKeyPolicy::TrustOnFirstUse), Err(KnownHostsError::BadSignature)));
+        assert!(hosts.get(HOST).is_none());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn malformed_lines_are_rejected() {
+        let path = hosts_path("malformed");
+        fs::write(&path, "# hosts\nfiles.example:7000 0011\n").unwrap();
+        assert!(matches!(KnownHosts::load(&path), Err(KnownHostsError::InvalidLine(2))));
+
+        fs::write(&path, "files.example:7000\n").unwrap();
+        assert!(matches!(KnownHosts::load(&path), Err(KnownHostsError::InvalidLine(1))));
+        fs::remove_file(path).unwrap();
+    }
+}
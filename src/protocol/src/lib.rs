@@ -1,14 +1,22 @@
 mod packet;
 mod errors;
+pub mod bitmap;
 pub mod enums;
 mod utils;
 pub mod context;
+pub mod listing;
+pub mod limits;
+pub mod known_hosts;
+pub mod hex;
+pub mod permissions;
+pub mod quota;
 
 use std::io::Error;
 use packet::*;
 use utils::*;
 use enums::*;
 use context::*;
+use permissions::required_permission;
 
 pub fn proceed_retry(ctx: &mut ProtocolContext) {
     let response = generate_status_retry_response_packet(ctx);
@@ -20,6 +28,21 @@ pub fn proceed_error(ctx: &mut ProtocolContext) {
     ctx.set_response(response);
 }
 
+pub fn proceed_denied(ctx: &mut ProtocolContext) {
+    let response = generate_status_error_response_packet(ctx, FieldStatus::Denied);
+    ctx.set_response(response);
+}
+
+pub fn proceed_quota_exceeded(ctx: &mut ProtocolContext) {
+    let response = generate_status_error_response_packet(ctx, FieldStatus::QuotaExceeded);
+    ctx.set_response(response);
+}
+
+pub fn proceed_insufficient_storage(ctx: &mut ProtocolContext) {
+    let response = generate_status_error_response_packet(ctx, FieldStatus::InsufficientStorage);
+    ctx.set_response(response);
+}
+
 fn handle_close(ctx: &mut ProtocolContext, request: &Packet) -> Action {
     if request.get_fields_count() != 0 {
         ctx.set_err_msg(String::from("Not valid count of fields for close method"));
@@ -33,9 +56,254 @@ fn handle_close(ctx: &mut ProtocolContext, request: &Packet) -> Action {
     Action::SendResponse(NextAction::Terminate)
 }
 
-fn handle_start_download(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+fn handle_handshake(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if ctx.get_public_key().is_empty() {
+        // The optional third field lists the cipher suites the client supports
+        if !(2..=3).contains(&request.get_fields_count()) {
+            ctx.set_err_msg(String::from("Not valid count of fields for handshake method"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        if request.get_fields()[1].get_field_type() != FieldType::PublicKey as u8 {
+            ctx.set_err_msg(String::from("Second field should be PublicKey"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        if request.get_fields()[1].get_field_data().len() != PUBLIC_KEY_SIZE {
+            ctx.set_err_msg(String::from("Not valid public key length"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        let mut offered_suites = Vec::new();
+        if let Some(field) = request.get_fields().get(2) {
+            if field.get_field_type() != FieldType::CipherSuites as u8 {
+                ctx.set_err_msg(String::from("Third field should be CipherSuites"));
+                let response = generate_error_response_packet(ctx);
+                ctx.set_response(response);
+                return Action::SendError;
+            }
+
+            offered_suites = String::from_utf8_lossy(field.get_field_data()).split(',')
+                .map(|suite| suite.trim().to_string()).filter(|suite| !suite.is_empty()).collect();
+        }
+
+        ctx.set_peer_public_key(Vec::from(request.get_fields()[1].get_field_data()));
+        ctx.set_offered_suites(offered_suites);
+        return Action::RequestHandShake;
+    }
+
+    let response = generate_status_handshake_response_packet(ctx);
+    ctx.reset();
+    ctx.set_response(response);
+    ctx.set_secured(true);
+    Action::SendResponse(NextAction::HandShake)
+}
+
+pub fn proceed_data_chunk(ctx: &mut ProtocolContext, chunk_id: u32, data_chunk: Vec<u8>) {
+    ctx.set_data_chunk(data_chunk);
+    let response = generate_status_sent_response_packet(ctx, chunk_id);
+    ctx.set_response(response);
+}
+
+fn handle_auth(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if !ctx.get_auth_verified() {
+        if ctx.get_authenticated() {
+            ctx.set_err_msg(String::from("Already authenticated"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        if request.get_fields_count() != 3 {
+            ctx.set_err_msg(String::from("Not valid count of fields for auth method"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        // A client key comes with a signature over the handshake transcript instead of a password
+        if request.get_fields()[1].get_field_type() == FieldType::ClientKey as u8 {
+            return handle_key_auth(ctx, request);
+        }
+
+        if request.get_fields()[1].get_field_type() != FieldType::Username as u8 {
+            ctx.set_err_msg(String::from("Second field should be Username or ClientKey"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        if request.get_fields()[2].get_field_type() != FieldType::Password as u8 {
+            ctx.set_err_msg(String::from("Third field should be Password"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        let username = String::from_utf8_lossy(request.get_fields()[1].get_field_data()).to_string();
+        if username.is_empty() {
+            ctx.set_err_msg(String::from("Username is empty"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        let password = String::from_utf8_lossy(request.get_fields()[2].get_field_data()).to_string();
+        ctx.set_credentials(username, password);
+        return Action::RequestAuth;
+    }
+
+    let response = generate_status_ok_response_packet(ctx);
+    ctx.reset();
+    ctx.set_response(response);
+    Action::SendResponse(NextAction::Done)
+}
+
+fn handle_delete(ctx: &mut ProtocolContext, request: &Packet) -> Action {
     if !ctx.get_file_open() {
         if request.get_fields_count() != 2 {
+            ctx.set_err_msg(String::from("Not valid count of fields for delete method"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        if request.get_fields()[1].get_field_type() != FieldType::Path as u8 {
+            ctx.set_err_msg(String::from("Second field should be Path"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        let path = String::from_utf8_lossy(request.get_fields()[1].get_field_data()).to_string();
+        if let Err(error) = ctx.get_limits().check_path(&path) {
+            ctx.set_err_msg(Error::from(error).to_string());
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        ctx.set_file_path(path);
+        return Action::RequestDelete;
+    }
+
+    let response = generate_status_ok_response_packet(ctx);
+    ctx.reset();
+    ctx.set_response(response);
+    Action::SendResponse(NextAction::Done)
+}
+
+fn handle_permissions(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if !ctx.get_file_open() {
+        if request.get_fields_count() != 3 {
+            ctx.set_err_msg(String::from("Not valid count of fields for permissions method"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        if request.get_fields()[1].get_field_type() != FieldType::Username as u8
+            || request.get_fields()[2].get_field_type() != FieldType::Path as u8 {
+            ctx.set_err_msg(String::from("Fields should be Username and Path"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        let user = String::from_utf8_lossy(request.get_fields()[1].get_field_data()).to_string();
+        let path = String::from_utf8_lossy(request.get_fields()[2].get_field_data()).to_string();
+        if let Err(error) = ctx.get_limits().check_path(&path) {
+            ctx.set_err_msg(Error::from(error).to_string());
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        ctx.set_query_user(user);
+        ctx.set_file_path(path);
+        return Action::RequestPermissions;
+    }
+
+    let response = generate_permissions_response_packet(ctx);
+    ctx.reset();
+    ctx.set_response(response);
+    Action::SendResponse(NextAction::Done)
+}
+
+fn handle_usage(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if !ctx.get_file_open() {
+        if request.get_fields_count() != 1 && request.get_fields_count() != 2 {
+            ctx.set_err_msg(String::from("Not valid count of fields for usage method"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        // Without a path only the user's own quota is reported
+        let mut path = String::from(".");
+        if request.get_fields_count() == 2 {
+            if request.get_fields()[1].get_field_type() != FieldType::Path as u8 {
+                ctx.set_err_msg(String::from("Second field should be Path"));
+                let response = generate_error_response_packet(ctx);
+                ctx.set_response(response);
+                return Action::SendError;
+            }
+
+            path = String::from_utf8_lossy(request.get_fields()[1].get_field_data()).to_string();
+            if let Err(error) = ctx.get_limits().check_path(&path) {
+                ctx.set_err_msg(Error::from(error).to_string());
+                let response = generate_error_response_packet(ctx);
+                ctx.set_response(response);
+                return Action::SendError;
+            }
+        }
+
+        ctx.set_file_path(path);
+        return Action::RequestUsage;
+    }
+
+    let response = generate_usage_response_packet(ctx);
+    ctx.reset();
+    ctx.set_response(response);
+    Action::SendResponse(NextAction::Done)
+}
+
+fn handle_key_auth(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if request.get_fields()[2].get_field_type() != FieldType::Signature as u8 {
+        ctx.set_err_msg(String::from("Third field should be Signature"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    if request.get_fields()[1].get_field_data().len() != CLIENT_KEY_SIZE {
+        ctx.set_err_msg(String::from("Not valid client key length"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    if request.get_fields()[2].get_field_data().len() != SIGNATURE_SIZE {
+        ctx.set_err_msg(String::from("Not valid signature length"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    ctx.set_key_proof(Vec::from(request.get_fields()[1].get_field_data()),
+                      Vec::from(request.get_fields()[2].get_field_data()));
+    Action::RequestAuth
+}
+
+fn handle_start_download(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if !ctx.get_file_open() {
+        if request.get_fields_count() < 2 || request.get_fields_count() > 5 {
             ctx.set_err_msg(String::from("Not valid count of fields for download method"));
             let response = generate_error_response_packet(ctx);
             ctx.set_response(response);
@@ -58,10 +326,62 @@ fn handle_start_download(ctx: &mut ProtocolContext, request: &Packet) -> Action
                 return Action::SendError;
             }
         };
+
+        if let Err(error) = ctx.get_limits().check_path(&path_str) {
+            ctx.set_err_msg(Error::from(error).to_string());
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
         ctx.set_file_path(path_str);
+
+        // WindowSize, Offset and Length are optional and may come in any order
+        for field in &request.get_fields()[2..] {
+            let field_type = match FieldType::try_from(field.get_field_type()) {
+                Ok(field_type @ (FieldType::WindowSize | FieldType::Offset | FieldType::Length)) => field_type,
+                _ => {
+                    ctx.set_err_msg(String::from("Only WindowSize, Offset and Length may follow Path"));
+                    let response = generate_error_response_packet(ctx);
+                    ctx.set_response(response);
+                    return Action::SendError;
+                }
+            };
+
+            let value = match parse_u64(field.get_field_data()) {
+                Ok(value) => value,
+                Err(error) => {
+                    ctx.set_err_msg(Error::from(error).to_string());
+                    let response = generate_error_response_packet(ctx);
+                    ctx.set_response(response);
+                    return Action::SendError;
+                }
+            };
+
+            if field_type == FieldType::WindowSize && value == 0 {
+                ctx.set_err_msg(String::from("Window size should be positive"));
+                let response = generate_error_response_packet(ctx);
+                ctx.set_response(response);
+                return Action::SendError;
+            }
+
+            match field_type {
+                FieldType::WindowSize => ctx.set_window_size(value.min(MAX_WINDOW_SIZE as u64) as u32),
+                FieldType::Offset => ctx.set_range_offset(value),
+                _ => ctx.set_range_length(Some(value)),
+            }
+        }
+
         return Action::RequestFileInfoRead;
     }
 
+    if ctx.get_window_size() > 0 {
+        let response = generate_status_ready_response_packet(ctx);
+        ctx.set_response(response);
+        ctx.set_started(true);
+        ctx.queue_window(Vec::new());
+        return Action::SendResponse(NextAction::SendWindow);
+    }
+
     if ctx.get_data_chunk().is_empty() {
         let response = generate_status_ready_response_packet(ctx);
         ctx.set_response(response);
@@ -74,16 +394,101 @@ fn handle_start_download(ctx: &mut ProtocolContext, request: &Packet) -> Action
         return Action::SendResponse(NextAction::None);
     }
 
-    let response = generate_status_sent_response_packet(ctx);
+    let response = generate_status_sent_response_packet(ctx, ctx.get_current_chunk_id());
     ctx.set_response(response);
     ctx.set_started(true);
     Action::SendResponse(NextAction::ReadData)
 }
 
+// Start and Continue both carry Path and FileSize, only the action asked from the session differs
+fn handle_upload_info(ctx: &mut ProtocolContext, request: &Packet, action: Action) -> Action {
+    if request.get_fields_count() != 3 {
+        ctx.set_err_msg(String::from("Not valid count of fields for upload method"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    if request.get_fields()[1].get_field_type() != FieldType::Path as u8 {
+        ctx.set_err_msg(String::from("Second field should be Path"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    let path_str = match parse_str(request.get_fields()[1].get_field_data()) {
+        Ok(value) => value,
+        Err(error) => {
+            ctx.set_err_msg(Error::from(error).to_string());
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+    };
+
+    if let Err(error) = ctx.get_limits().check_path(&path_str) {
+        ctx.set_err_msg(Error::from(error).to_string());
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+    ctx.set_file_path(path_str);
+
+    if request.get_fields()[2].get_field_type() != FieldType::FileSize as u8 {
+        ctx.set_err_msg(String::from("Third field should be FileSize"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    let file_size = match parse_u64(request.get_fields()[2].get_field_data()) {
+        Ok(value) => value,
+        Err(error) => {
+            ctx.set_err_msg(Error::from(error).to_string());
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+    };
+
+    if let Err(error) = ctx.get_limits().check_upload_size(file_size) {
+        ctx.set_err_msg(Error::from(error).to_string());
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    ctx.set_file_size(file_size);
+    action
+}
+
 fn handle_start_upload(ctx: &mut ProtocolContext, request: &Packet) -> Action {
     if !ctx.get_file_open() {
-        if request.get_fields_count() != 3 {
-            ctx.set_err_msg(String::from("Not valid count of fields for upload method"));
+        return handle_upload_info(ctx, request, Action::RequestFileInfoWrite);
+    }
+
+    ctx.init_received_chunks();
+    let response = generate_status_ready_response_packet(ctx);
+    ctx.set_response(response);
+    ctx.set_started(true);
+    Action::SendResponse(NextAction::None)
+}
+
+fn handle_continue_upload(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if !ctx.get_file_open() {
+        return handle_upload_info(ctx, request, Action::RequestFileInfoResume);
+    }
+
+    let response = generate_status_resume_response_packet(ctx);
+    ctx.set_response(response);
+    ctx.set_started(true);
+    Action::SendResponse(NextAction::None)
+}
+
+fn handle_start_list(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if !ctx.get_file_open() {
+        if request.get_fields_count() != 2 {
+            ctx.set_err_msg(String::from("Not valid count of fields for list method"));
             let response = generate_error_response_packet(ctx);
             ctx.set_response(response);
             return Action::SendError;
@@ -105,27 +510,15 @@ fn handle_start_upload(ctx: &mut ProtocolContext, request: &Packet) -> Action {
                 return Action::SendError;
             }
         };
-        ctx.set_file_path(path_str);
 
-        if request.get_fields()[2].get_field_type() != FieldType::FileSize as u8 {
-            ctx.set_err_msg(String::from("Third field should be FileSize"));
+        if let Err(error) = ctx.get_limits().check_path(&path_str) {
+            ctx.set_err_msg(Error::from(error).to_string());
             let response = generate_error_response_packet(ctx);
             ctx.set_response(response);
             return Action::SendError;
         }
-
-        let file_size = match parse_u64(request.get_fields()[2].get_field_data()) {
-            Ok(value) => value,
-            Err(error) => {
-                ctx.set_err_msg(Error::from(error).to_string());
-                let response = generate_error_response_packet(ctx);
-                ctx.set_response(response);
-                return Action::SendError;
-            }
-        };
-
-        ctx.set_file_size(file_size);
-        return Action::RequestFileInfoWrite;
+        ctx.set_file_path(path_str);
+        return Action::RequestListing;
     }
 
     let response = generate_status_ready_response_packet(ctx);
@@ -134,6 +527,27 @@ fn handle_start_upload(ctx: &mut ProtocolContext, request: &Packet) -> Action {
     Action::SendResponse(NextAction::None)
 }
 
+fn handle_list_next(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if request.get_fields_count() != 1 {
+        ctx.set_err_msg(String::from("Not valid count of fields"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    if ctx.get_current_chunk_id() >= ctx.get_chunk_count() {
+        ctx.set_err_msg(String::from("List page id out of range"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    ctx.increment_current_chunk_id();
+    let response = generate_status_list_page_response_packet(ctx);
+    ctx.set_response(response);
+    Action::SendResponse(NextAction::None)
+}
+
 fn handle_next(ctx: &mut ProtocolContext, request: &Packet) -> Action {
     if request.get_fields_count() != 1 {
         ctx.set_err_msg(String::from("Not valid count of fields"));
@@ -142,6 +556,13 @@ fn handle_next(ctx: &mut ProtocolContext, request: &Packet) -> Action {
         return Action::SendError;
     }
 
+    if ctx.get_window_size() > 0 {
+        ctx.set_err_msg(String::from("Windowed download expects Ack"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
     if ctx.get_current_chunk_id() > ctx.get_chunk_count() {
         ctx.set_err_msg(String::from("File chunk id out of range"));
         let response = generate_error_response_packet(ctx);
@@ -149,7 +570,7 @@ fn handle_next(ctx: &mut ProtocolContext, request: &Packet) -> Action {
         return Action::SendError;
     }
 
-    let response = generate_status_sent_response_packet(ctx);
+    let response = generate_status_sent_response_packet(ctx, ctx.get_current_chunk_id());
     ctx.set_response(response);
 
     if ctx.get_current_chunk_id() < ctx.get_chunk_count() {
@@ -158,16 +579,92 @@ fn handle_next(ctx: &mut ProtocolContext, request: &Packet) -> Action {
     Action::SendResponse(NextAction::None)
 }
 
-fn handle_send(ctx: &mut ProtocolContext, request: &Packet) -> Action {
-    if request.get_fields_count() != 3 {
+fn handle_ack(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if ctx.get_window_size() == 0 {
+        ctx.set_err_msg(String::from("Download is not windowed"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    if request.get_fields_count() != 2 && request.get_fields_count() != 3 {
         ctx.set_err_msg(String::from("Not valid count of fields"));
         let response = generate_error_response_packet(ctx);
         ctx.set_response(response);
         return Action::SendError;
     }
 
-    if ctx.get_current_chunk_id() + 1 > ctx.get_chunk_count() {
-        ctx.set_err_msg(String::from("File chunk id out of range"));
+    if request.get_fields()[1].get_field_type() != FieldType::ChunkID as u8 {
+        ctx.set_err_msg(String::from("Second field should be ChunkID"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    let chunk_id = match parse_u64(request.get_fields()[1].get_field_data()) {
+        Ok(chunk_id) => chunk_id,
+        Err(error) => {
+            ctx.set_err_msg(Error::from(error).to_string());
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+    };
+
+    if chunk_id > ctx.get_sent_chunk_id() as u64 {
+        ctx.set_err_msg(String::from("Acknowledged chunk id was not sent"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    // A reordered older Ack must not move the window back
+    if chunk_id as u32 > ctx.get_acked_chunk_id() {
+        ctx.set_acked_chunk_id(chunk_id as u32);
+    }
+
+    let mut missing: Vec<u32> = Vec::new();
+    if request.get_fields_count() == 3 {
+        if request.get_fields()[2].get_field_type() != FieldType::Missing as u8 {
+            ctx.set_err_msg(String::from("Third field should be Missing"));
+            let response = generate_error_response_packet(ctx);
+            ctx.set_response(response);
+            return Action::SendError;
+        }
+
+        let chunk_ids = match parse_u64_list(request.get_fields()[2].get_field_data()) {
+            Ok(chunk_ids) => chunk_ids,
+            Err(error) => {
+                ctx.set_err_msg(Error::from(error).to_string());
+                let response = generate_error_response_packet(ctx);
+                ctx.set_response(response);
+                return Action::SendError;
+            }
+        };
+
+        for chunk_id in chunk_ids {
+            if chunk_id <= ctx.get_acked_chunk_id() as u64 || chunk_id > ctx.get_sent_chunk_id() as u64 {
+                ctx.set_err_msg(String::from("Missing chunk id out of window"));
+                let response = generate_error_response_packet(ctx);
+                ctx.set_response(response);
+                return Action::SendError;
+            }
+
+            if !missing.contains(&(chunk_id as u32)) {
+                missing.push(chunk_id as u32);
+            }
+        }
+    }
+
+    let response = generate_status_acked_response_packet(ctx);
+    ctx.set_response(response);
+    ctx.queue_window(missing);
+    Action::SendResponse(NextAction::SendWindow)
+}
+
+fn handle_send(ctx: &mut ProtocolContext, request: &Packet) -> Action {
+    if request.get_fields_count() != 3 {
+        ctx.set_err_msg(String::from("Not valid count of fields"));
         let response = generate_error_response_packet(ctx);
         ctx.set_response(response);
         return Action::SendError;
@@ -197,18 +694,36 @@ fn handle_send(ctx: &mut ProtocolContext, request: &Packet) -> Action {
         }
     };
 
-    if ctx.get_current_chunk_id() as u64 + 1 != chunk_id {
-        let err_msg = "Excepted ".to_owned() + u64_to_str(ctx.get_current_chunk_id() as u64)
-            .to_string().as_str() + " in chunk_id, but found " + u64_to_str(chunk_id).to_string().as_str();
+    if chunk_id == 0 || chunk_id > ctx.get_chunk_count() as u64 {
+        let err_msg = "File chunk id ".to_owned() + u64_to_str(chunk_id).as_str() + " out of range";
         ctx.set_err_msg(err_msg);
         let response = generate_error_response_packet(ctx);
         ctx.set_response(response);
         return Action::SendError;
     }
 
-    ctx.set_data_chunk(Vec::from(request.get_fields()[2].get_field_data()));
+    let data_chunk = request.get_fields()[2].get_field_data();
+    let excepted_length = match chunk_id == ctx.get_chunk_count() as u64 {
+        true => ctx.get_file_size() - (chunk_id - 1) * FILE_CHUNK_SIZE as u64,
+        false => FILE_CHUNK_SIZE as u64,
+    };
+    if data_chunk.len() as u64 != excepted_length {
+        ctx.set_err_msg(String::from("Not valid data chunk length"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    ctx.set_current_chunk_id(chunk_id as u32);
     let response = generate_status_received_response_packet(ctx);
     ctx.set_response(response);
+
+    // A chunk that came twice is acknowledged again but written once
+    if ctx.get_received_chunks().contains(chunk_id as u32) {
+        return Action::SendResponse(NextAction::None);
+    }
+
+    ctx.set_data_chunk(Vec::from(data_chunk));
     Action::SendResponse(NextAction::WriteData)
 }
 
@@ -220,7 +735,7 @@ fn handle_retry(ctx: &mut ProtocolContext, request: &Packet) -> Action {
         return Action::SendError;
     }
 
-    Action::SendResponse(NextAction::None)
+    Action::SendResponse(NextAction::Resend)
 }
 
 fn handle_end(ctx: &mut ProtocolContext, request: &Packet) -> Action {
@@ -231,7 +746,17 @@ fn handle_end(ctx: &mut ProtocolContext, request: &Packet) -> Action {
         return Action::SendError;
     }
 
-    if ctx.get_current_chunk_id() != ctx.get_chunk_count() {
+    if ctx.get_current_method() == PacketMethod::Upload as u8 && !ctx.get_received_chunks().is_complete() {
+        let response = generate_status_missing_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendResponse(NextAction::None);
+    }
+
+    let last_chunk_id = match ctx.get_window_size() {
+        0 => ctx.get_current_chunk_id(),
+        _ => ctx.get_acked_chunk_id(),
+    };
+    if ctx.get_current_method() != PacketMethod::Upload as u8 && last_chunk_id != ctx.get_chunk_count() {
         ctx.set_err_msg(String::from("File chunks not ended"));
         let response = generate_error_response_packet(ctx);
         ctx.set_response(response);
@@ -259,7 +784,7 @@ fn handle_cancel(ctx: &mut ProtocolContext, request: &Packet) -> Action {
 }
 
 pub fn proceed_request(ctx: &mut ProtocolContext, request_raw: &[u8]) -> Action {
-    let request = match Packet::parse(&request_raw) {
+    let request = match Packet::parse(request_raw, &ctx.get_limits()) {
         Ok(packet) => packet,
         Err(error) => {
             ctx.set_err_msg(Error::from(error).to_string());
@@ -270,15 +795,37 @@ pub fn proceed_request(ctx: &mut ProtocolContext, request_raw: &[u8]) -> Action
     };
 
     let method = request.get_method();
+    // Close ends the session even in the middle of a transfer
+    if method == PacketMethod::Close as u8 {
+        ctx.set_current_method(method);
+        return handle_close(ctx, &request);
+    }
+
     if !ctx.get_started() { ctx.set_current_method(method); }
 
-    if method != request.get_method() {
+    if method != ctx.get_current_method() {
         ctx.set_err_msg(String::from("Method isn\'t match"));
         let response = generate_error_response_packet(ctx);
         ctx.set_response(response);
         return Action::SendError;
     }
 
+    if !ctx.get_secured() && method != PacketMethod::HandShake as u8 {
+        ctx.set_err_msg(String::from("Handshake required"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
+    // Once users are configured only the handshake, auth and close work without an account
+    if ctx.get_auth_required() && !ctx.get_authenticated() && method != PacketMethod::HandShake as u8
+        && method != PacketMethod::Auth as u8 && method != PacketMethod::Close as u8 {
+        ctx.set_err_msg(String::from("Authentication required"));
+        let response = generate_error_response_packet(ctx);
+        ctx.set_response(response);
+        return Action::SendError;
+    }
+
     let mut command = 0;
     if request.get_fields_count() > 0 {
         if request.get_fields()[0].get_field_type() != FieldType::Command as u8 {
@@ -297,8 +844,33 @@ pub fn proceed_request(ctx: &mut ProtocolContext, request_raw: &[u8]) -> Action
         }
     }
 
-    if !ctx.get_started() && method == PacketMethod::Close as u8 {
-        return handle_close(ctx, &request);
+    if let (Ok(packet_method), Ok(field_command)) = (PacketMethod::try_from(method), FieldCommand::try_from(command))
+        && let Some(permission) = required_permission(packet_method, field_command)
+        && !ctx.has_permission(permission) {
+        ctx.set_err_msg(format!("Permission {} required", permission.get_name()));
+        let response = generate_status_error_response_packet(ctx, FieldStatus::Denied);
+        ctx.set_response(response);
+        return Action::SendResponse(NextAction::Denied);
+    }
+
+    if !ctx.get_started() && method == PacketMethod::HandShake as u8 && command == FieldCommand::Start as u8 {
+        return handle_handshake(ctx, &request);
+    }
+
+    if !ctx.get_started() && method == PacketMethod::Auth as u8 && command == FieldCommand::Start as u8 {
+        return handle_auth(ctx, &request);
+    }
+
+    if !ctx.get_started() && method == PacketMethod::Delete as u8 && command == FieldCommand::Start as u8 {
+        return handle_delete(ctx, &request);
+    }
+
+    if !ctx.get_started() && method == PacketMethod::Permissions as u8 && command == FieldCommand::Start as u8 {
+        return handle_permissions(ctx, &request);
+    }
+
+    if !ctx.get_started() && method == PacketMethod::Usage as u8 && command == FieldCommand::Start as u8 {
+        return handle_usage(ctx, &request);
     }
 
     if !ctx.get_started() && method == PacketMethod::Download as u8 && command == FieldCommand::Start as u8 {
@@ -309,26 +881,42 @@ pub fn proceed_request(ctx: &mut ProtocolContext, request_raw: &[u8]) -> Action
         return handle_start_upload(ctx, &request);
     }
 
+    if !ctx.get_started() && method == PacketMethod::Upload as u8 && command == FieldCommand::Continue as u8 {
+        return handle_continue_upload(ctx, &request);
+    }
+
+    if !ctx.get_started() && method == PacketMethod::List as u8 && command == FieldCommand::Start as u8 {
+        return handle_start_list(ctx, &request);
+    }
+
+    if ctx.get_started() && method == PacketMethod::List as u8 && command == FieldCommand::Next as u8 {
+        return handle_list_next(ctx, &request);
+    }
+
     if ctx.get_started() && method == PacketMethod::Download as u8 && command == FieldCommand::Next as u8 {
         return handle_next(ctx, &request);
     }
 
+    if ctx.get_started() && method == PacketMethod::Download as u8 && command == FieldCommand::Ack as u8 {
+        return handle_ack(ctx, &request);
+    }
+
     if ctx.get_started() && method == PacketMethod::Upload as u8 && command == FieldCommand::Send as u8 {
         return handle_send(ctx, &request);
     }
 
-    if (method == PacketMethod::Download as u8 || method == PacketMethod::Upload as u8)
-        && command == FieldCommand::Retry as u8 {
+    if (method == PacketMethod::Download as u8 || method == PacketMethod::Upload as u8
+        || method == PacketMethod::List as u8) && command == FieldCommand::Retry as u8 {
         return handle_retry(ctx, &request);
     }
 
-    if ctx.get_started() && (method == PacketMethod::Download as u8 ||
-        method == PacketMethod::Upload as u8) && command == FieldCommand::End as u8 {
+    if ctx.get_started() && (method == PacketMethod::Download as u8 || method == PacketMethod::Upload as u8
+        || method == PacketMethod::List as u8) && command == FieldCommand::End as u8 {
         return handle_end(ctx, &request);
     }
 
-    if ctx.get_started() && (method == PacketMethod::Download as u8 ||
-        method == PacketMethod::Upload as u8) && command == FieldCommand::Cancel as u8 {
+    if ctx.get_started() && (method == PacketMethod::Download as u8 || method == PacketMethod::Upload as u8
+        || method == PacketMethod::List as u8) && command == FieldCommand::Cancel as u8 {
         return handle_cancel(ctx, &request);
     }
 
@@ -348,19 +936,69 @@ fn generate_error_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
     Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
 }
 
+fn generate_permissions_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
+    let names = ctx.get_effective_permissions().iter().map(|permission| permission.get_name())
+        .collect::<Vec<&str>>().join(",");
+    let resp_fields = vec![
+        PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Ok as u8]),
+        PacketField::new(FieldType::Permissions as u8, names.len() as u16, names.into_bytes())
+    ];
+
+    Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
+}
+
+fn generate_usage_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
+    let mut resp_fields = vec![PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Ok as u8])];
+    for entry in ctx.get_usage() {
+        let bytes = entry.get_bytes();
+        resp_fields.push(PacketField::new(FieldType::Quota as u8, bytes.len() as u16, bytes));
+    }
+
+    Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
+}
+
+fn generate_status_error_response_packet(ctx: &ProtocolContext, status: FieldStatus) -> Vec<u8> {
+    let resp_fields = vec![
+        PacketField::new(FieldType::Status as u8, 1, vec![status as u8]),
+        PacketField::new(FieldType::ErrorMsg as u8, ctx.get_err_msg().len() as u16,
+                         ctx.get_err_msg().as_bytes().to_vec())
+    ];
+
+    Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
+}
+
 fn generate_status_ready_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
     let session_id_str = u64_to_u8_vec(ctx.get_session_id() as u64);
     let chunk_size_str = u64_to_u8_vec(FILE_CHUNK_SIZE as u64);
     let chunk_count_str = u64_to_u8_vec(ctx.get_chunk_count() as u64);
     if ctx.get_current_method() == PacketMethod::Download as u8 {
         let file_size_str = u64_to_u8_vec(ctx.get_file_size());
-        let resp_fields = vec![
+        let offset_str = u64_to_u8_vec(ctx.get_range_offset());
+        let length_str = u64_to_u8_vec(ctx.get_range_length().unwrap_or(0));
+        let mut resp_fields = vec![
             PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Ready as u8]),
             PacketField::new(FieldType::SessionID as u8, session_id_str.len() as u16, session_id_str),
             PacketField::new(FieldType::FileSize as u8, file_size_str.len() as u16, file_size_str),
+            PacketField::new(FieldType::Offset as u8, offset_str.len() as u16, offset_str),
+            PacketField::new(FieldType::Length as u8, length_str.len() as u16, length_str),
             PacketField::new(FieldType::ChunkSize as u8, chunk_size_str.len() as u16, chunk_size_str),
             PacketField::new(FieldType::ChunksCount as u8, chunk_count_str.len() as u16, chunk_count_str)
         ];
+        if ctx.get_window_size() > 0 {
+            let window_size_str = u64_to_u8_vec(ctx.get_window_size() as u64);
+            resp_fields.push(PacketField::new(FieldType::WindowSize as u8, window_size_str.len() as u16,
+                                              window_size_str));
+        }
+
+        return Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes();
+    }
+
+    if ctx.get_current_method() == PacketMethod::List as u8 {
+        let resp_fields = vec![
+            PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Ready as u8]),
+            PacketField::new(FieldType::SessionID as u8, session_id_str.len() as u16, session_id_str),
+            PacketField::new(FieldType::ChunksCount as u8, chunk_count_str.len() as u16, chunk_count_str)
+        ];
 
         return Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes();
     }
@@ -375,8 +1013,56 @@ fn generate_status_ready_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
     Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
 }
 
-fn generate_status_sent_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
-    let chunk_id = u64_to_u8_vec(ctx.get_current_chunk_id() as u64);
+// Ready plus where to pick up: the first missing chunk (0 when nothing is missing) and the missing ids
+fn generate_status_resume_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
+    let session_id_str = u64_to_u8_vec(ctx.get_session_id() as u64);
+    let chunk_size_str = u64_to_u8_vec(FILE_CHUNK_SIZE as u64);
+    let chunk_count_str = u64_to_u8_vec(ctx.get_chunk_count() as u64);
+    let missing: Vec<u64> = ctx.get_received_chunks().missing(MAX_MISSING_REPORTED).iter()
+        .map(|chunk_id| *chunk_id as u64).collect();
+    let chunk_id_str = u64_to_u8_vec(missing.first().copied().unwrap_or(0));
+    let missing_str = u64_list_to_u8_vec(&missing);
+    let resp_fields = vec![
+        PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Ready as u8]),
+        PacketField::new(FieldType::SessionID as u8, session_id_str.len() as u16, session_id_str),
+        PacketField::new(FieldType::ChunkSize as u8, chunk_size_str.len() as u16, chunk_size_str),
+        PacketField::new(FieldType::ChunksCount as u8, chunk_count_str.len() as u16, chunk_count_str),
+        PacketField::new(FieldType::ChunkID as u8, chunk_id_str.len() as u16, chunk_id_str),
+        PacketField::new(FieldType::Missing as u8, missing_str.len() as u16, missing_str),
+    ];
+
+    Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
+}
+
+fn generate_status_handshake_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
+    let session_id_str = u64_to_u8_vec(ctx.get_session_id() as u64);
+    let public_key = ctx.get_public_key();
+
+    let mut resp_fields = vec![
+        PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Ok as u8]),
+        PacketField::new(FieldType::SessionID as u8, session_id_str.len() as u16, session_id_str),
+        PacketField::new(FieldType::PublicKey as u8, public_key.len() as u16, public_key.to_vec()),
+    ];
+    if !ctx.get_cipher_suite().is_empty() {
+        let suite = ctx.get_cipher_suite().as_bytes().to_vec();
+        resp_fields.push(PacketField::new(FieldType::CipherSuite as u8, suite.len() as u16, suite));
+    }
+    if !ctx.get_host_key().is_empty() {
+        let host_key = ctx.get_host_key().to_vec();
+        let signature = ctx.get_host_signature().to_vec();
+        resp_fields.push(PacketField::new(FieldType::HostKey as u8, host_key.len() as u16, host_key));
+        resp_fields.push(PacketField::new(FieldType::Signature as u8, signature.len() as u16, signature));
+    }
+    if ctx.get_redirect_port() != 0 {
+        let port_str = u64_to_u8_vec(ctx.get_redirect_port() as u64);
+        resp_fields.push(PacketField::new(FieldType::Port as u8, port_str.len() as u16, port_str));
+    }
+
+    Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
+}
+
+fn generate_status_sent_response_packet(ctx: &ProtocolContext, chunk_id: u32) -> Vec<u8> {
+    let chunk_id = u64_to_u8_vec(chunk_id as u64);
     let data_chunk: &[u8] = ctx.get_data_chunk();
 
     let resp_fields: Vec<PacketField> = vec![
@@ -388,9 +1074,47 @@ fn generate_status_sent_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
     Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
 }
 
+fn generate_status_list_page_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
+    let chunk_id = u64_to_u8_vec(ctx.get_current_chunk_id() as u64);
+
+    let mut resp_fields: Vec<PacketField> = vec![
+        PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Sent as u8]),
+        PacketField::new(FieldType::ChunkID as u8, chunk_id.len() as u16, chunk_id),
+    ];
+    for entry in ctx.get_list_page() {
+        resp_fields.push(PacketField::new(FieldType::Entry as u8, entry.len() as u16, entry.clone()));
+    }
+
+    Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
+}
+
 fn generate_status_received_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
+    let chunk_id = u64_to_u8_vec(ctx.get_current_chunk_id() as u64);
     let resp_fields: Vec<PacketField> = vec![
-        PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Received as u8])
+        PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Received as u8]),
+        PacketField::new(FieldType::ChunkID as u8, chunk_id.len() as u16, chunk_id),
+    ];
+
+    Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
+}
+
+fn generate_status_missing_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
+    let missing: Vec<u64> = ctx.get_received_chunks().missing(MAX_MISSING_REPORTED).iter()
+        .map(|chunk_id| *chunk_id as u64).collect();
+    let missing_str = u64_list_to_u8_vec(&missing);
+    let resp_fields: Vec<PacketField> = vec![
+        PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Missing as u8]),
+        PacketField::new(FieldType::Missing as u8, missing_str.len() as u16, missing_str),
+    ];
+
+    Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
+}
+
+fn generate_status_acked_response_packet(ctx: &ProtocolContext) -> Vec<u8> {
+    let chunk_id = u64_to_u8_vec(ctx.get_acked_chunk_id() as u64);
+    let resp_fields: Vec<PacketField> = vec![
+        PacketField::new(FieldType::Status as u8, 1, vec![FieldStatus::Received as u8]),
+        PacketField::new(FieldType::ChunkID as u8, chunk_id.len() as u16, chunk_id),
     ];
 
     Packet::new(ctx.get_current_method(), resp_fields.len() as u8, resp_fields).get_bytes()
@@ -0,0 +1,96 @@
+use super::errors::LimitError;
+
+const DEFAULT_MAX_PATH_LENGTH: usize = 4096;
+const DEFAULT_MAX_PATH_DEPTH: usize = 64;
+const DEFAULT_MAX_UPLOAD_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+const DEFAULT_MAX_ERR_MSG_LENGTH: usize = 1024;
+const DEFAULT_MAX_FIELDS: u8 = 16;
+const DEFAULT_MAX_FIELD_LENGTH: u16 = 8192;
+
+// Server side bounds on what a client may ask for, DataChunk is bounded by FILE_CHUNK_SIZE instead
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    max_path_length: usize,
+    max_path_depth: usize,
+    max_upload_size: u64,
+    max_err_msg_length: usize,
+    max_fields: u8,
+    max_field_length: u16,
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Limits { max_path_length: DEFAULT_MAX_PATH_LENGTH, max_path_depth: DEFAULT_MAX_PATH_DEPTH,
+            max_upload_size: DEFAULT_MAX_UPLOAD_SIZE, max_err_msg_length: DEFAULT_MAX_ERR_MSG_LENGTH,
+            max_fields: DEFAULT_MAX_FIELDS, max_field_length: DEFAULT_MAX_FIELD_LENGTH }
+    }
+
+    pub fn check_path(&self, path_str: &str) -> Result<(), LimitError> {
+        if path_str.len() > self.max_path_length {
+            return Err(LimitError::PathTooLong);
+        }
+
+        let depth = path_str.split('/').filter(|name| !name.is_empty() && *name != ".").count();
+        if depth > self.max_path_depth {
+            return Err(LimitError::PathTooDeep);
+        }
+
+        Ok(())
+    }
+
+    pub fn check_upload_size(&self, size: u64) -> Result<(), LimitError> {
+        if size > self.max_upload_size {
+            return Err(LimitError::FileTooLarge);
+        }
+
+        Ok(())
+    }
+
+    pub fn get_max_path_length(&self) -> usize { self.max_path_length }
+    pub fn get_max_path_depth(&self) -> usize { self.max_path_depth }
+    pub fn get_max_upload_size(&self) -> u64 { self.max_upload_size }
+    pub fn get_max_err_msg_length(&self) -> usize { self.max_err_msg_length }
+    pub fn get_max_fields(&self) -> u8 { self.max_fields }
+    pub fn get_max_field_length(&self) -> u16 { self.max_field_length }
+
+    pub fn set_max_path_length(&mut self, max_path_length: usize) { self.max_path_length = max_path_length; }
+    pub fn set_max_path_depth(&mut self, max_path_depth: usize) { self.max_path_depth = max_path_depth; }
+    pub fn set_max_upload_size(&mut self, max_upload_size: u64) { self.max_upload_size = max_upload_size; }
+    pub fn set_max_err_msg_length(&mut self, max_err_msg_length: usize) {
+        self.max_err_msg_length = max_err_msg_length;
+    }
+    pub fn set_max_fields(&mut self, max_fields: u8) { self.max_fields = max_fields; }
+    pub fn set_max_field_length(&mut self, max_field_length: u16) { self.max_field_length = max_field_length; }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_are_bounded_by_length_and_depth() {
+        let mut limits = Limits::new();
+        limits.set_max_path_length(16);
+        limits.set_max_path_depth(2);
+
+        assert!(limits.check_path("a/b").is_ok());
+        assert!(limits.check_path("./a//b/.").is_ok());
+        assert!(matches!(limits.check_path("a/b/c"), Err(LimitError::PathTooDeep)));
+        assert!(matches!(limits.check_path(&"a".repeat(17)), Err(LimitError::PathTooLong)));
+    }
+
+    #[test]
+    fn upload_size_is_bounded() {
+        let mut limits = Limits::new();
+        limits.set_max_upload_size(100);
+
+        assert!(limits.check_upload_size(100).is_ok());
+        assert!(matches!(limits.check_upload_size(101), Err(LimitError::FileTooLarge)));
+    }
+}
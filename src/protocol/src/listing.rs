@@ -0,0 +1,39 @@
+use super::enums::EntryKind;
+use super::utils::u64_to_u8_vec;
+
+pub const ENTRY_SEPARATOR: u8 = b':';
+
+pub struct ListEntry {
+    kind: EntryKind,
+    name: String,
+    size: u64,
+    modified: u64,
+}
+
+impl ListEntry {
+    pub fn new(kind: EntryKind, name: String, size: u64, modified: u64) -> Self {
+        ListEntry { kind, name, size, modified }
+    }
+
+    // Layout: <kind>:<size>:<modified>:<name>, name goes last so it may contain the separator
+    pub fn get_bytes(&self) -> Vec<u8> {
+        let size = u64_to_u8_vec(self.size);
+        let modified = u64_to_u8_vec(self.modified);
+
+        let mut bytes = Vec::with_capacity(4 + size.len() + modified.len() + self.name.len());
+        bytes.push(self.kind as u8);
+        bytes.push(ENTRY_SEPARATOR);
+        bytes.extend_from_slice(&size);
+        bytes.push(ENTRY_SEPARATOR);
+        bytes.extend_from_slice(&modified);
+        bytes.push(ENTRY_SEPARATOR);
+        bytes.extend_from_slice(self.name.as_bytes());
+
+        bytes
+    }
+
+    pub fn get_kind(&self) -> EntryKind { self.kind }
+    pub fn get_name(&self) -> &str { &self.name }
+    pub fn get_size(&self) -> u64 { self.size }
+    pub fn get_modified(&self) -> u64 { self.modified }
+}
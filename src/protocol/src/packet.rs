@@ -1,6 +1,7 @@
 use std::collections::HashSet;
 use super::errors::ParseError;
-use super::enums::{PacketMethod, FieldType, EOF};
+use super::enums::{PacketMethod, FieldType, EOF, FILE_CHUNK_SIZE};
+use super::limits::Limits;
 
 pub struct PacketField {
     field_type: u8,
@@ -29,7 +30,7 @@ impl Packet {
         Packet { method, fields_count, fields}
     }
 
-    pub fn parse(raw_data: &[u8]) -> Result<Packet, ParseError> {
+    pub fn parse(raw_data: &[u8], limits: &Limits) -> Result<Packet, ParseError> {
         if raw_data.len() < 2 {
             return Err(ParseError::NotValidHeaderLength);
         }
@@ -41,6 +42,10 @@ impl Packet {
         }
 
         let fields_count: u8 = raw_data[i]; i += 1;
+        if fields_count > limits.get_max_fields() {
+            return Err(ParseError::TooManyFields);
+        }
+
         let mut fields: Vec<PacketField> = Vec::with_capacity(fields_count as usize);
         let mut seen_types: HashSet<u8> = HashSet::with_capacity(fields_count as usize);
 
@@ -54,7 +59,7 @@ impl Packet {
                 return Err(ParseError::NotValidFieldType);
             }
 
-            if !seen_types.insert(field_type) {
+            if field_type != FieldType::Entry as u8 && !seen_types.insert(field_type) {
                 return Err(ParseError::DuplicateFieldFound);
             }
 
@@ -64,7 +69,16 @@ impl Packet {
             }
             field_data_length -= 1;
 
-            if i + field_data_length as usize > raw_data.len() {
+            let mut max_field_length = limits.get_max_field_length();
+            if field_type == FieldType::DataChunk as u8 {
+                max_field_length = FILE_CHUNK_SIZE;
+            }
+
+            if field_data_length > max_field_length {
+                return Err(ParseError::FieldTooLong);
+            }
+
+            if i + field_data_length as usize >= raw_data.len() {
                 return Err(ParseError::NotValidFieldDataLength);
             }
             if raw_data[i + field_data_length as usize] != EOF {
@@ -96,7 +110,7 @@ impl Packet {
         for i in 0..self.get_fields().len() {
             bytes.push(self.get_fields()[i].get_field_type());
             bytes.extend_from_slice(&(self.get_fields()[i].get_field_data_length() + 1).to_be_bytes());
-            bytes.extend_from_slice(&self.get_fields()[i].get_field_data());
+            bytes.extend_from_slice(self.get_fields()[i].get_field_data());
             bytes.push(EOF);
         }
 
@@ -106,4 +120,48 @@ impl Packet {
     pub fn get_method(&self) -> u8 { self.method }
     pub fn get_fields_count(&self) -> u8 { self.fields_count }
     pub fn get_fields(&self) -> &Vec<PacketField> { &self.fields }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raw_packet(fields: &[(FieldType, &[u8])]) -> Vec<u8> {
+        let mut raw = vec![PacketMethod::List as u8, fields.len() as u8];
+        for (field_type, data) in fields {
+            raw.push(*field_type as u8);
+            raw.extend_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+            raw.extend_from_slice(data);
+            raw.push(EOF);
+        }
+        raw
+    }
+
+    #[test]
+    fn field_count_is_limited() {
+        let mut limits = Limits::new();
+        let raw = raw_packet(&[(FieldType::Command, b"0"), (FieldType::Path, b"a")]);
+        assert!(Packet::parse(&raw, &limits).is_ok());
+
+        limits.set_max_fields(1);
+        assert!(matches!(Packet::parse(&raw, &limits), Err(ParseError::TooManyFields)));
+    }
+
+    #[test]
+    fn field_length_is_limited_except_for_data_chunks() {
+        let mut limits = Limits::new();
+        limits.set_max_field_length(4);
+        assert!(Packet::parse(&raw_packet(&[(FieldType::Path, b"abcd")]), &limits).is_ok());
+        assert!(matches!(Packet::parse(&raw_packet(&[(FieldType::Path, b"abcde")]), &limits),
+                         Err(ParseError::FieldTooLong)));
+        assert!(Packet::parse(&raw_packet(&[(FieldType::DataChunk, &[0; 64])]), &limits).is_ok());
+    }
+
+    #[test]
+    fn fields_must_end_with_a_terminator() {
+        let limits = Limits::new();
+        let mut raw = raw_packet(&[(FieldType::Path, b"abc")]);
+        raw.pop();
+        assert!(matches!(Packet::parse(&raw, &limits), Err(ParseError::NotValidFieldDataLength)));
+    }
+}
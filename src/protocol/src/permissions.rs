@@ -0,0 +1,60 @@
+use super::enums::{FieldCommand, PacketMethod};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+    List,
+    Admin,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 5] =
+        [Permission::Read, Permission::Write, Permission::Delete, Permission::List, Permission::Admin];
+
+    pub fn get_name(&self) -> &'static str {
+        match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Delete => "delete",
+            Permission::List => "list",
+            Permission::Admin => "admin",
+        }
+    }
+
+    pub fn modifies(&self) -> bool {
+        matches!(self, Permission::Write | Permission::Delete)
+    }
+}
+
+impl TryFrom<&str> for Permission {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "read" => Ok(Permission::Read),
+            "write" => Ok(Permission::Write),
+            "delete" => Ok(Permission::Delete),
+            "list" => Ok(Permission::List),
+            "admin" => Ok(Permission::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+// Without a role policy every session may do everything but administration
+pub const DEFAULT_PERMISSIONS: [Permission; 4] =
+    [Permission::Read, Permission::Write, Permission::Delete, Permission::List];
+
+pub fn required_permission(method: PacketMethod, command: FieldCommand) -> Option<Permission> {
+    match (method, command) {
+        (_, FieldCommand::End | FieldCommand::Cancel) => None,
+        (PacketMethod::Download, _) => Some(Permission::Read),
+        (PacketMethod::Upload, _) => Some(Permission::Write),
+        (PacketMethod::List | PacketMethod::Usage, _) => Some(Permission::List),
+        (PacketMethod::Delete, _) => Some(Permission::Delete),
+        (PacketMethod::Permissions, _) => Some(Permission::Admin),
+        (PacketMethod::Standard | PacketMethod::HandShake | PacketMethod::Auth | PacketMethod::Close, _) => None,
+    }
+}
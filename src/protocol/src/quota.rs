@@ -0,0 +1,43 @@
+use super::enums::QuotaScope;
+use super::listing::ENTRY_SEPARATOR;
+use super::utils::u64_to_u8_vec;
+
+pub struct QuotaEntry {
+    scope: QuotaScope,
+    name: String,
+    used_bytes: u64,
+    max_bytes: Option<u64>,
+    used_files: u64,
+    max_files: Option<u64>,
+}
+
+impl QuotaEntry {
+    pub fn new(scope: QuotaScope, name: String, used_bytes: u64, max_bytes: Option<u64>, used_files: u64,
+               max_files: Option<u64>) -> Self {
+        QuotaEntry { scope, name, used_bytes, max_bytes, used_files, max_files }
+    }
+
+    // Layout: <scope>:<used bytes>:<max bytes>:<used files>:<max files>:<name>, an unlimited max is left empty
+    pub fn get_bytes(&self) -> Vec<u8> {
+        let numbers = [Some(self.used_bytes), self.max_bytes, Some(self.used_files), self.max_files];
+
+        let mut bytes = vec![self.scope as u8];
+        for number in numbers {
+            bytes.push(ENTRY_SEPARATOR);
+            if let Some(number) = number {
+                bytes.extend_from_slice(&u64_to_u8_vec(number));
+            }
+        }
+        bytes.push(ENTRY_SEPARATOR);
+        bytes.extend_from_slice(self.name.as_bytes());
+
+        bytes
+    }
+
+    pub fn get_scope(&self) -> QuotaScope { self.scope }
+    pub fn get_name(&self) -> &str { &self.name }
+    pub fn get_used_bytes(&self) -> u64 { self.used_bytes }
+    pub fn get_max_bytes(&self) -> Option<u64> { self.max_bytes }
+    pub fn get_used_files(&self) -> u64 { self.used_files }
+    pub fn get_max_files(&self) -> Option<u64> { self.max_files }
+}
@@ -1,30 +1,31 @@
 use std::result::Result;
 use super::errors::UtilError;
 
+const LIST_SEPARATOR: u8 = b',';
+
 pub fn parse_u64(bytes: &[u8]) -> Result<u64, UtilError> {
-    if bytes.len() > 20 || (bytes.len() == 20 && bytes[0] > 0x31) {
-        return Err(UtilError::UIntOverflow);
+    if bytes.is_empty() {
+        return Err(UtilError::NumberParseError);
     }
 
-    let mut i = (bytes.len() - 1) as u8;
     let mut result: u64 = 0;
     for byte in bytes {
-        if 0x30 <= *byte && *byte <= 0x39 {
-            result += ((*byte - 0x30) as u64) * 10_u64.pow(i as u32);
-
-            if i != 0 {
-                i -= 1;
-            }
-            continue;
+        if !byte.is_ascii_digit() {
+            return Err(UtilError::NumberParseError);
         }
 
-        return Err(UtilError::NumberParseError);
+        result = result.checked_mul(10).and_then(|result| result.checked_add((*byte - 0x30) as u64))
+            .ok_or(UtilError::UIntOverflow)?;
     }
 
     Ok(result)
 }
 
 pub fn u64_to_u8_vec(value: u64) -> Vec<u8> {
+    if value == 0 {
+        return vec![0x30];
+    }
+
     let mut result: Vec<u8> = Vec::with_capacity(20);
 
     let mut num = value;
@@ -38,6 +39,10 @@ pub fn u64_to_u8_vec(value: u64) -> Vec<u8> {
 }
 
 pub fn u64_to_str(value: u64) -> String {
+    if value == 0 {
+        return String::from("0");
+    }
+
     let mut symbols: Vec<u8> = Vec::with_capacity(20);
 
     let mut num = value;
@@ -69,4 +74,55 @@ pub fn parse_str(bytes: &[u8]) -> Result<String, UtilError> {
     }
 
     Ok(result)
-}
\ No newline at end of file
+}
+
+pub fn parse_u64_list(bytes: &[u8]) -> Result<Vec<u64>, UtilError> {
+    let mut result: Vec<u64> = Vec::new();
+    for item in bytes.split(|byte| *byte == LIST_SEPARATOR) {
+        if item.is_empty() {
+            return Err(UtilError::NumberParseError);
+        }
+
+        result.push(parse_u64(item)?);
+    }
+
+    Ok(result)
+}
+
+pub fn u64_list_to_u8_vec(values: &[u64]) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::with_capacity(values.len() * 4);
+    for (i, value) in values.iter().enumerate() {
+        if i != 0 {
+            result.push(LIST_SEPARATOR);
+        }
+        result.extend_from_slice(&u64_to_u8_vec(*value));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_u64_reads_the_full_range() {
+        assert_eq!(parse_u64(b"0").unwrap(), 0);
+        assert_eq!(parse_u64(b"00042").unwrap(), 42);
+        assert_eq!(parse_u64(b"18446744073709551615").unwrap(), u64::MAX);
+    }
+
+    #[test]
+    fn parse_u64_rejects_overflow() {
+        assert!(matches!(parse_u64(b"18446744073709551616"), Err(UtilError::UIntOverflow)));
+        assert!(matches!(parse_u64(b"19999999999999999999"), Err(UtilError::UIntOverflow)));
+        assert!(matches!(parse_u64(b"100000000000000000000"), Err(UtilError::UIntOverflow)));
+    }
+
+    #[test]
+    fn parse_u64_rejects_non_digits() {
+        assert!(matches!(parse_u64(b""), Err(UtilError::NumberParseError)));
+        assert!(matches!(parse_u64(b"-1"), Err(UtilError::NumberParseError)));
+        assert!(matches!(parse_u64(b"12a"), Err(UtilError::NumberParseError)));
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use protocol::enums::QuotaScope;
+use protocol::quota::QuotaEntry;
+use super::filesystem::{get_disk_usage, DiskUsage};
+
+const MAX_SCAN_ATTEMPTS: u32 = 3;
+
+#[derive(Debug)]
+pub enum QuotaError {
+    BytesExceeded(String),
+    FilesExceeded(String),
+    ReservationExceeded,
+    ScanFailed,
+}
+
+impl From<QuotaError> for Error {
+    fn from(error: QuotaError) -> Error {
+        match error {
+            QuotaError::BytesExceeded(scope) => Error::other(format!("Quota exceeded: no bytes left for {}", scope)),
+            QuotaError::FilesExceeded(scope) => Error::other(format!("Quota exceeded: no files left for {}", scope)),
+            QuotaError::ReservationExceeded => Error::other("Quota exceeded: upload is larger than declared"),
+            QuotaError::ScanFailed => Error::other("Quota usage scan failed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QuotaLimits {
+    max_bytes: Option<u64>,
+    max_files: Option<u64>,
+}
+
+impl QuotaLimits {
+    pub fn new(max_bytes: Option<u64>, max_files: Option<u64>) -> Self {
+        QuotaLimits { max_bytes, max_files }
+    }
+
+    fn is_limited(&self) -> bool { self.max_bytes.is_some() || self.max_files.is_some() }
+}
+
+struct Scope {
+    kind: QuotaScope,
+    name: String,
+    path: PathBuf,
+    limits: QuotaLimits,
+}
+
+impl Scope {
+    fn get_label(&self) -> String {
+        match self.kind {
+            QuotaScope::User => format!("user {}", self.name),
+            QuotaScope::Directory => format!("directory {}", self.name),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Reserved {
+    bytes: u64,
+    files: u64,
+}
+
+// Moves on whenever reserved bytes turn into bytes on disk or are given back
+#[derive(Default)]
+struct Ledger {
+    reserved: HashMap<PathBuf, Reserved>,
+    generation: u64,
+}
+
+// User quotas count everything under the user's root, directory quotas a top-level directory of the storage root
+#[derive(Clone)]
+pub struct Quotas {
+    root: PathBuf,
+    default_user: QuotaLimits,
+    users: HashMap<String, QuotaLimits>,
+    directories: HashMap<String, QuotaLimits>,
+    ledger: Arc<Mutex<Ledger>>,
+}
+
+impl Quotas {
+    pub fn new(root: &Path, default_user: QuotaLimits) -> Self {
+        Quotas { root: root.to_path_buf(), default_user, users: HashMap::new(), directories: HashMap::new(),
+            ledger: Arc::new(Mutex::new(Ledger::default())) }
+    }
+
+    pub fn set_user(&mut self, user: String, limits: QuotaLimits) { self.users.insert(user, limits); }
+    pub fn set_directory(&mut self, directory: String, limits: QuotaLimits) {
+        self.directories.insert(directory, limits);
+    }
+
+    // Users without a root of their own share the storage root, so only directory quotas apply to them
+    fn get_scopes(&self, user: &str, user_root: Option<&Path>, path: &Path) -> Vec<Scope> {
+        let mut scopes = Vec::new();
+        if let Some(user_root) = user_root {
+            let limits = self.users.get(user).copied().unwrap_or(self.default_user);
+            scopes.push(Scope { kind: QuotaScope::User, name: user.to_string(), path: user_root.to_path_buf(),
+                limits });
+        }
+
+        // Only paths inside a directory count for it, not a file named like it
+        let mut components = path.strip_prefix(&self.root).map(|relative| relative.components()).into_iter().flatten();
+        if let (Some(Component::Normal(directory)), Some(_)) = (components.next(), components.next()) {
+            let directory = directory.to_string_lossy().into_owned();
+            if let Some(limits) = self.directories.get(&directory) {
+                scopes.push(Scope { kind: QuotaScope::Directory, path: self.root.join(&directory), name: directory,
+                    limits: *limits });
+            }
+        }
+
+        scopes
+    }
+
+    pub fn get_usage(&self, user: &str, user_root: Option<&Path>, path: &Path) -> Result<Vec<QuotaEntry>, QuotaError> {
+        self.get_scopes(user, user_root, path).into_iter()
+            .map(|scope| {
+                let usage = get_disk_usage(&scope.path).map_err(|_| QuotaError::ScanFailed)?;
+                Ok(QuotaEntry::new(scope.kind, scope.name, usage.get_bytes(), scope.limits.max_bytes,
+                                   usage.get_files(), scope.limits.max_files))
+            })
+            .collect()
+    }
+
+    // Called when an upload starts with the bytes it will still write, the upload will end as one more file
+    pub fn reserve(&self, user: &str, user_root: Option<&Path>, path: &Path, bytes: u64)
+        -> Result<Reservation, QuotaError> {
+        let scopes: Vec<Scope> = self.get_scopes(user, user_root, path).into_iter()
+            .filter(|scope| scope.limits.is_limited())
+            .collect();
+
+        // Scans run unlocked, they are only trusted if no upload wrote or ended meanwhile
+        let mut attempts = 0;
+        let (mut ledger, usages) = loop {
+            attempts += 1;
+            let generation = self.ledger.lock().map_err(|_| QuotaError::ScanFailed)?.generation;
+            let usages = scan(&scopes)?;
+            let ledger = self.ledger.lock().map_err(|_| QuotaError::ScanFailed)?;
+            if ledger.generation == generation {
+                break (ledger, usages);
+            }
+            if attempts >= MAX_SCAN_ATTEMPTS {
+                let usages = scan(&scopes)?;
+                break (ledger, usages);
+            }
+        };
+
+        for (scope, usage) in scopes.iter().zip(&usages) {
+            let others = ledger.reserved.get(&scope.path).copied().unwrap_or_default();
+            if let Some(max_bytes) = scope.limits.max_bytes && usage.get_bytes() + others.bytes + bytes > max_bytes {
+                return Err(QuotaError::BytesExceeded(scope.get_label()));
+            }
+            if let Some(max_files) = scope.limits.max_files && usage.get_files() + others.files + 1 > max_files {
+                return Err(QuotaError::FilesExceeded(scope.get_label()));
+            }
+        }
+
+        let paths: Vec<PathBuf> = scopes.into_iter().map(|scope| scope.path).collect();
+        for path in &paths {
+            let entry = ledger.reserved.entry(path.clone()).or_default();
+            entry.bytes += bytes;
+            entry.files += 1;
+        }
+
+        Ok(Reservation { ledger: self.ledger.clone(), paths, bytes })
+    }
+}
+
+fn scan(scopes: &[Scope]) -> Result<Vec<DiskUsage>, QuotaError> {
+    scopes.iter().map(|scope| get_disk_usage(&scope.path).map_err(|_| QuotaError::ScanFailed)).collect()
+}
+
+// Released when dropped, by then whatever was written is on disk and counted by the scans
+pub struct Reservation {
+    ledger: Arc<Mutex<Ledger>>,
+    paths: Vec<PathBuf>,
+    bytes: u64,
+}
+
+impl Reservation {
+    pub fn consume(&mut self, bytes: u64) -> Result<(), QuotaError> {
+        if bytes > self.bytes {
+            return Err(QuotaError::ReservationExceeded);
+        }
+
+        self.bytes -= bytes;
+        let mut ledger = self.ledger.lock().map_err(|_| QuotaError::ScanFailed)?;
+        ledger.generation += 1;
+        for path in &self.paths {
+            if let Some(entry) = ledger.reserved.get_mut(path) {
+                entry.bytes = entry.bytes.saturating_sub(bytes);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let Ok(mut ledger) = self.ledger.lock() else { return };
+        ledger.generation += 1;
+        for path in &self.paths {
+            if let Some(entry) = ledger.reserved.get_mut(path) {
+                entry.bytes = entry.bytes.saturating_sub(self.bytes);
+                entry.files = entry.files.saturating_sub(1);
+                if entry.bytes == 0 && entry.files == 0 {
+                    ledger.reserved.remove(path);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use crate::test_utils::temp_dir;
+    use super::*;
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = temp_dir(name);
+        fs::create_dir_all(root.join("home/alice")).unwrap();
+        root
+    }
+
+    #[test]
+    fn reservations_count_against_the_quota() {
+        let root = temp_root("quotas-reserve");
+        let home = root.join("home/alice");
+        fs::write(home.join("existing"), [0u8; 40]).unwrap();
+        let quotas = Quotas::new(&root, QuotaLimits::new(Some(100), None));
+
+        let first = quotas.reserve("alice", Some(&home), &home.join("a"), 50).unwrap();
+        assert!(matches!(quotas.reserve("alice", Some(&home), &home.join("b"), 20),
+                         Err(QuotaError::BytesExceeded(_))));
+
+        drop(first);
+        assert!(quotas.reserve("alice", Some(&home), &home.join("b"), 20).is_ok());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn consumed_bytes_move_to_the_disk() {
+        let root = temp_root("quotas-consume");
+        let home = root.join("home/alice");
+        let quotas = Quotas::new(&root, QuotaLimits::new(Some(100), None));
+
+        let mut reservation = quotas.reserve("alice", Some(&home), &home.join("a"), 60).unwrap();
+        assert!(matches!(reservation.consume(61), Err(QuotaError::ReservationExceeded)));
+        reservation.consume(30).unwrap();
+        fs::write(home.join("a"), [0u8; 30]).unwrap();
+
+        assert!(quotas.reserve("alice", Some(&home), &home.join("b"), 41).is_err());
+        assert!(quotas.reserve("alice", Some(&home), &home.join("b"), 40).is_ok());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn file_counts_include_uploads_in_progress() {
+        let root = temp_root("quotas-files");
+        let home = root.join("home/alice");
+        fs::write(home.join("existing"), b"x").unwrap();
+        let quotas = Quotas::new(&root, QuotaLimits::new(None, Some(2)));
+
+        let _first = quotas.reserve("alice", Some(&home), &home.join("a"), 1).unwrap();
+        assert!(matches!(quotas.reserve("alice", Some(&home), &home.join("b"), 1),
+                         Err(QuotaError::FilesExceeded(_))));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn directory_quotas_apply_inside_the_directory_only() {
+        let root = temp_root("quotas-directory");
+        fs::create_dir_all(root.join("shared")).unwrap();
+        let mut quotas = Quotas::new(&root, QuotaLimits::default());
+        quotas.set_directory(String::from("shared"), QuotaLimits::new(Some(10), None));
+
+        assert!(quotas.reserve("bob", None, &root.join("shared/a"), 11).is_err());
+        assert!(quotas.reserve("bob", None, &root.join("shared"), 11).is_ok());
+        assert!(quotas.reserve("bob", None, &root.join("other/a"), 11).is_ok());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn users_without_a_root_only_get_directory_quotas() {
+        let root = temp_root("quotas-shared-root");
+        let quotas = Quotas::new(&root, QuotaLimits::new(Some(10), None));
+
+        assert!(quotas.reserve("bob", None, &root.join("a"), 11).is_ok());
+        assert!(quotas.get_usage("bob", None, &root.join("a")).unwrap().is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
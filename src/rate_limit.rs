@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Past this many addresses the ones with an elapsed window are forgotten
+const MAX_TRACKED_ADDRS: usize = 4096;
+
+// Allows each address a number of events per window, a window starts with the first event after the last one ended
+pub struct RateLimiter {
+    max_events: u32,
+    window: Duration,
+    windows: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    pub fn new(max_events: u32, window: Duration) -> Self {
+        RateLimiter { max_events, window, windows: HashMap::new() }
+    }
+
+    pub fn allow(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        if self.windows.len() >= MAX_TRACKED_ADDRS {
+            let window = self.window;
+            self.windows.retain(|_, (start, _)| now.duration_since(*start) < window);
+        }
+
+        let (start, events) = self.windows.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *events = 0;
+        }
+        if *events >= self.max_events {
+            return false;
+        }
+
+        *events += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use super::*;
+
+    #[test]
+    fn each_address_has_its_own_budget() {
+        let first = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let second = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(3600));
+        assert!(limiter.allow(first));
+        assert!(limiter.allow(first));
+        assert!(!limiter.allow(first));
+        assert!(limiter.allow(second));
+    }
+
+    #[test]
+    fn budgets_refill_after_the_window() {
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let mut limiter = RateLimiter::new(1, Duration::ZERO);
+        assert!(limiter.allow(ip));
+        assert!(limiter.allow(ip));
+    }
+}
@@ -0,0 +1,253 @@
+use std::mem::take;
+use std::time::{Duration, Instant};
+
+const DEFAULT_INITIAL_RTO: Duration = Duration::from_secs(1);
+const DEFAULT_MIN_RTO: Duration = Duration::from_millis(200);
+const DEFAULT_MAX_RTO: Duration = Duration::from_secs(10);
+const DEFAULT_MAX_RETRANSMISSIONS: u32 = 5;
+const DEFAULT_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const REPLAY_WINDOW_SIZE: u32 = 64;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    initial_rto: Duration,
+    min_rto: Duration,
+    max_rto: Duration,
+    max_retransmissions: u32,
+    session_idle: Duration,
+}
+
+impl Timeouts {
+    pub fn new() -> Self {
+        Timeouts { initial_rto: DEFAULT_INITIAL_RTO, min_rto: DEFAULT_MIN_RTO, max_rto: DEFAULT_MAX_RTO,
+            max_retransmissions: DEFAULT_MAX_RETRANSMISSIONS, session_idle: DEFAULT_SESSION_IDLE_TIMEOUT }
+    }
+
+    pub fn set_initial_rto(&mut self, initial_rto: Duration) { self.initial_rto = initial_rto; }
+    pub fn set_min_rto(&mut self, min_rto: Duration) { self.min_rto = min_rto; }
+    pub fn set_max_rto(&mut self, max_rto: Duration) { self.max_rto = max_rto; }
+    pub fn set_max_retransmissions(&mut self, max_retransmissions: u32) {
+        self.max_retransmissions = max_retransmissions;
+    }
+    pub fn set_session_idle(&mut self, session_idle: Duration) { self.session_idle = session_idle; }
+
+    pub fn get_min_rto(&self) -> Duration { self.min_rto }
+    pub fn get_max_rto(&self) -> Duration { self.max_rto }
+}
+
+pub enum Delivery {
+    New,
+    // Same sequence as the last accepted request, its answer is sent again
+    Duplicate,
+    Replayed,
+}
+
+pub enum Timeout {
+    Retransmit,
+    Wait,
+    Expired,
+}
+
+// Retransmission timeout estimation as described in RFC 6298
+struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    rto: Duration,
+    timeouts: Timeouts,
+}
+
+impl RttEstimator {
+    fn new(timeouts: Timeouts) -> Self {
+        RttEstimator { srtt: None, rttvar: Duration::ZERO, rto: timeouts.initial_rto, timeouts }
+    }
+
+    fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = srtt.abs_diff(rtt);
+                self.rttvar = (self.rttvar * 3 + delta) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+
+        let srtt = self.srtt.unwrap_or(self.timeouts.initial_rto);
+        self.rto = (srtt + self.rttvar * 4).clamp(self.timeouts.min_rto, self.timeouts.max_rto);
+    }
+
+    fn backoff(&mut self) {
+        self.rto = (self.rto * 2).min(self.timeouts.max_rto);
+    }
+}
+
+// Sliding window over received sequence numbers as in RFC 4303, bit i stands for highest - i
+struct ReplayWindow {
+    highest: Option<u32>,
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        ReplayWindow { highest: None, seen: 0 }
+    }
+
+    fn check(&self, sequence: u32) -> Delivery {
+        let highest = match self.highest {
+            Some(highest) => highest,
+            None => return Delivery::New,
+        };
+
+        if sequence > highest {
+            return Delivery::New;
+        }
+
+        if sequence == highest {
+            return Delivery::Duplicate;
+        }
+
+        let offset = highest - sequence;
+        if offset >= REPLAY_WINDOW_SIZE || self.seen & (1 << offset) != 0 {
+            return Delivery::Replayed;
+        }
+
+        Delivery::New
+    }
+
+    fn mark(&mut self, sequence: u32) {
+        match self.highest {
+            Some(highest) if sequence <= highest => self.seen |= 1 << (highest - sequence),
+            Some(highest) => {
+                let shift = sequence - highest;
+                self.seen = if shift >= REPLAY_WINDOW_SIZE { 0 } else { self.seen << shift };
+                self.seen |= 1;
+                self.highest = Some(sequence);
+            }
+            None => {
+                self.seen = 1;
+                self.highest = Some(sequence);
+            }
+        }
+    }
+}
+
+pub struct DeliveryState {
+    rtt: RttEstimator,
+    received: ReplayWindow,
+    sent_sequence: u32,
+    datagrams: Vec<Vec<u8>>,
+    previous: Vec<Vec<u8>>,
+    sent_at: Option<Instant>,
+    retransmissions: u32,
+    last_activity: Instant,
+}
+
+impl DeliveryState {
+    pub fn new(timeouts: Timeouts) -> Self {
+        DeliveryState { rtt: RttEstimator::new(timeouts), received: ReplayWindow::new(), sent_sequence: 0,
+            datagrams: Vec::new(), previous: Vec::new(), sent_at: None, retransmissions: 0,
+            last_activity: Instant::now() }
+    }
+
+    pub fn check(&self, sequence: u32) -> Delivery {
+        self.received.check(sequence)
+    }
+
+    // Called once the request is known to be authentic, the answer to the previous one is now delivered
+    pub fn accept(&mut self, sequence: u32) {
+        if let Some(sent_at) = self.sent_at.take() && self.retransmissions == 0 {
+            self.rtt.sample(sent_at.elapsed());
+        }
+
+        self.received.mark(sequence);
+        self.previous = take(&mut self.datagrams);
+        self.retransmissions = 0;
+        self.last_activity = Instant::now();
+    }
+
+    // Every datagram the server sends gets its own sequence number, resent datagrams keep theirs
+    pub fn next_sequence(&mut self) -> u32 {
+        self.sent_sequence = self.sent_sequence.wrapping_add(1);
+        self.sent_sequence
+    }
+
+    pub fn record(&mut self, datagram: Vec<u8>) {
+        if self.sent_at.is_none() {
+            self.sent_at = Some(Instant::now());
+        }
+        self.datagrams.push(datagram);
+    }
+
+    // An explicit Retry is answered with whatever answered the request before it
+    pub fn restore_previous(&mut self) {
+        self.datagrams = self.previous.clone();
+    }
+
+    pub fn on_timeout(&mut self) -> Timeout {
+        if self.last_activity.elapsed() >= self.rtt.timeouts.session_idle {
+            return Timeout::Expired;
+        }
+
+        if self.retransmissions < self.rtt.timeouts.max_retransmissions && !self.datagrams.is_empty() {
+            self.retransmissions += 1;
+            self.rtt.backoff();
+            return Timeout::Retransmit;
+        }
+
+        Timeout::Wait
+    }
+
+    pub fn get_timeout(&self) -> Duration { self.rtt.rto }
+    pub fn get_datagrams(&self) -> &[Vec<u8>] { &self.datagrams }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_window_tells_new_duplicate_and_replayed_apart() {
+        let mut window = ReplayWindow::new();
+        assert!(matches!(window.check(5), Delivery::New));
+        window.mark(5);
+
+        assert!(matches!(window.check(5), Delivery::Duplicate));
+        assert!(matches!(window.check(6), Delivery::New));
+        // Reordered but not seen yet
+        assert!(matches!(window.check(3), Delivery::New));
+        window.mark(3);
+        assert!(matches!(window.check(3), Delivery::Replayed));
+    }
+
+    #[test]
+    fn replay_window_slides() {
+        let mut window = ReplayWindow::new();
+        window.mark(1);
+        window.mark(REPLAY_WINDOW_SIZE);
+        assert!(matches!(window.check(1), Delivery::Replayed));
+        assert!(matches!(window.check(2), Delivery::New));
+
+        window.mark(REPLAY_WINDOW_SIZE + 1);
+        assert!(matches!(window.check(1), Delivery::Replayed));
+        assert!(matches!(window.check(2), Delivery::New));
+        assert!(matches!(window.check(REPLAY_WINDOW_SIZE), Delivery::Replayed));
+
+        window.mark(10 * REPLAY_WINDOW_SIZE);
+        assert!(matches!(window.check(REPLAY_WINDOW_SIZE + 1), Delivery::Replayed));
+        assert!(matches!(window.check(10 * REPLAY_WINDOW_SIZE - 1), Delivery::New));
+    }
+
+    #[test]
+    fn accepted_requests_move_the_answer_to_previous() {
+        let mut state = DeliveryState::new(Timeouts::new());
+        state.record(vec![1]);
+        state.accept(1);
+        assert!(state.get_datagrams().is_empty());
+        assert!(matches!(state.check(1), Delivery::Duplicate));
+
+        state.restore_previous();
+        assert_eq!(state.get_datagrams(), &[vec![1]]);
+    }
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use log::{info, warn};
+use serde::Deserialize;
+use protocol::permissions::Permission;
+
+const ANY_USER: &str = "*";
+const GROUP_PREFIX: char = '@';
+
+#[derive(Debug)]
+pub enum RoleError {
+    ReadFailed,
+    ParseFailed(String),
+    InvalidPermission(String),
+    UnknownRole(String),
+    UnknownGroup(String),
+}
+
+impl From<RoleError> for Error {
+    fn from(error: RoleError) -> Error {
+        match error {
+            RoleError::ReadFailed => Error::other("Policy file read failed"),
+            RoleError::ParseFailed(cause) => Error::other(format!("Policy file parse failed: {}", cause)),
+            RoleError::InvalidPermission(permission) => {
+                Error::other(format!("Invalid permission {}, should be read, write, delete, list or admin", permission))
+            }
+            RoleError::UnknownRole(role) => Error::other(format!("Unknown role {}", role)),
+            RoleError::UnknownGroup(group) => Error::other(format!("Unknown group {}", group)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PolicyFile {
+    roles: HashMap<String, Vec<String>>,
+    groups: HashMap<String, Vec<String>>,
+    // User name, "@group" or "*" to role names
+    assignments: HashMap<String, Vec<String>>,
+}
+
+struct RolePolicy {
+    roles: HashMap<String, Vec<Permission>>,
+    groups: HashMap<String, Vec<String>>,
+    assignments: HashMap<String, Vec<String>>,
+}
+
+impl RolePolicy {
+    fn load(path: &Path) -> Result<Self, RoleError> {
+        let policy_str = fs::read_to_string(path).map_err(|_| RoleError::ReadFailed)?;
+        let file: PolicyFile = toml::from_str(&policy_str)
+            .map_err(|error| RoleError::ParseFailed(error.message().to_string()))?;
+
+        let mut roles = HashMap::new();
+        for (role, names) in file.roles {
+            let permissions = names.iter()
+                .map(|name| Permission::try_from(name.as_str()).map_err(|_| RoleError::InvalidPermission(name.clone())))
+                .collect::<Result<Vec<Permission>, RoleError>>()?;
+            roles.insert(role, permissions);
+        }
+
+        for (subject, assigned) in &file.assignments {
+            if let Some(group) = subject.strip_prefix(GROUP_PREFIX) && !file.groups.contains_key(group) {
+                return Err(RoleError::UnknownGroup(group.to_string()));
+            }
+            if let Some(role) = assigned.iter().find(|role| !roles.contains_key(*role)) {
+                return Err(RoleError::UnknownRole(role.clone()));
+            }
+        }
+
+        Ok(RolePolicy { roles, groups: file.groups, assignments: file.assignments })
+    }
+
+    fn get_permissions(&self, user: &str) -> Vec<Permission> {
+        let groups = self.groups.iter()
+            .filter(|(_, members)| members.iter().any(|member| member == user))
+            .map(|(group, _)| format!("{}{}", GROUP_PREFIX, group));
+        let subjects: Vec<String> = [ANY_USER.to_string(), user.to_string()].into_iter().chain(groups).collect();
+        let granted: Vec<Permission> = subjects.iter()
+            .filter_map(|subject| self.assignments.get(subject))
+            .flatten()
+            .filter_map(|role| self.roles.get(role))
+            .flatten()
+            .copied()
+            .collect();
+        Permission::ALL.into_iter().filter(|permission| granted.contains(permission)).collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct Roles {
+    path: PathBuf,
+    policy: Arc<RwLock<RolePolicy>>,
+}
+
+impl Roles {
+    pub fn load(path: &Path) -> Result<Self, RoleError> {
+        let policy = RolePolicy::load(path)?;
+        Ok(Roles { path: path.to_path_buf(), policy: Arc::new(RwLock::new(policy)) })
+    }
+
+    pub fn get_permissions(&self, user: &str) -> Vec<Permission> {
+        match self.policy.read() {
+            Ok(policy) => policy.get_permissions(user),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // Polls the modification time, a broken file keeps the previous policy in place
+    pub fn watch(&self, interval: Duration) {
+        let roles = self.clone();
+        thread::spawn(move || {
+            let mut modified = modified_time(&roles.path);
+            loop {
+                thread::sleep(interval);
+                let current = modified_time(&roles.path);
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+
+                match roles.reload() {
+                    Ok(()) => info!("Reloaded policy file {}", roles.path.display()),
+                    Err(error) => {
+                        warn!("Policy file {} not reloaded: {}", roles.path.display(), Error::from(error));
+                    }
+                }
+            }
+        });
+    }
+
+    fn reload(&self) -> Result<(), RoleError> {
+        let policy = RolePolicy::load(&self.path)?;
+        if let Ok(mut current_policy) = self.policy.write() {
+            *current_policy = policy;
+        }
+        Ok(())
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::temp_dir;
+    use super::*;
+
+    const POLICY: &str = r#"
+        [roles]
+        reader = ["read", "list"]
+        uploader = ["write"]
+
+        [groups]
+        staff = ["alice"]
+
+        [assignments]
+        "*" = ["reader"]
+        "@staff" = ["uploader"]
+    "#;
+
+    #[test]
+    fn permissions_are_the_union_of_assignments() {
+        let dir = temp_dir("roles-union");
+        let path = dir.join("roles.toml");
+        fs::write(&path, POLICY).unwrap();
+        let roles = Roles::load(&path).unwrap();
+
+        assert_eq!(roles.get_permissions("alice"), vec![Permission::Read, Permission::Write, Permission::List]);
+        assert_eq!(roles.get_permissions("bob"), vec![Permission::Read, Permission::List]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_roles_and_groups_are_rejected() {
+        let dir = temp_dir("roles-unknown");
+        let path = dir.join("roles.toml");
+        fs::write(&path, "[assignments]\nbob = [\"missing\"]\n").unwrap();
+        assert!(matches!(Roles::load(&path), Err(RoleError::UnknownRole(_))));
+
+        fs::write(&path, "[assignments]\n\"@missing\" = []\n").unwrap();
+        assert!(matches!(Roles::load(&path), Err(RoleError::UnknownGroup(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reload_swaps_the_policy_and_keeps_it_when_broken() {
+        let dir = temp_dir("roles-reload");
+        let path = dir.join("roles.toml");
+        fs::write(&path, POLICY).unwrap();
+        let roles = Roles::load(&path).unwrap();
+        let session_roles = roles.clone();
+
+        fs::write(&path, POLICY.replace("\"@staff\" = [\"uploader\"]", "")).unwrap();
+        roles.reload().unwrap();
+        assert_eq!(session_roles.get_permissions("alice"), vec![Permission::Read, Permission::List]);
+
+        fs::write(&path, "[roles]\nreader = [\"fly\"]\n").unwrap();
+        assert!(matches!(roles.reload(), Err(RoleError::InvalidPermission(_))));
+        assert_eq!(session_roles.get_permissions("alice"), vec![Permission::Read, Permission::List]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,228 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::result::Result;
+
+#[derive(Debug)]
+pub enum SandboxError {
+    RootNotFound,
+    AbsolutePath,
+    EscapesRoot,
+    SymlinkNotAllowed,
+    ResolveFailed,
+}
+
+impl From<SandboxError> for Error {
+    fn from(error: SandboxError) -> Error {
+        match error {
+            SandboxError::RootNotFound => Error::other("Server root not found"),
+            SandboxError::AbsolutePath => Error::other("Absolute paths are not allowed"),
+            SandboxError::EscapesRoot => Error::other("Path escapes the server root"),
+            SandboxError::SymlinkNotAllowed => Error::other("Symlinks are not allowed"),
+            SandboxError::ResolveFailed => Error::other("Path resolving failed"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    // Any symlink in the path is rejected
+    Deny,
+    // Symlinks are followed as long as the target stays under the root
+    WithinRoot,
+    // Symlinks are followed anywhere
+    Follow,
+}
+
+impl TryFrom<&str> for SymlinkPolicy {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "deny" => Ok(SymlinkPolicy::Deny),
+            "within-root" => Ok(SymlinkPolicy::WithinRoot),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+    symlinks: SymlinkPolicy,
+}
+
+impl Sandbox {
+    pub fn new(root_str: &str, symlinks: SymlinkPolicy) -> Result<Self, SandboxError> {
+        fs::create_dir_all(root_str).map_err(|_| SandboxError::RootNotFound)?;
+        Sandbox::open(root_str, symlinks)
+    }
+
+    // Like new, but the root has to exist already
+    pub fn open(root_str: &str, symlinks: SymlinkPolicy) -> Result<Self, SandboxError> {
+        let root = fs::canonicalize(root_str).map_err(|_| SandboxError::RootNotFound)?;
+        if !root.is_dir() {
+            return Err(SandboxError::RootNotFound);
+        }
+
+        Ok(Sandbox { root, symlinks })
+    }
+
+    pub fn resolve(&self, path_str: &str) -> Result<PathBuf, SandboxError> {
+        let mut components: Vec<&Path> = Vec::new();
+        for component in Path::new(path_str).components() {
+            match component {
+                Component::Prefix(_) | Component::RootDir => return Err(SandboxError::AbsolutePath),
+                Component::CurDir => (),
+                Component::ParentDir => {
+                    if components.pop().is_none() {
+                        return Err(SandboxError::EscapesRoot);
+                    }
+                }
+                Component::Normal(name) => components.push(Path::new(name)),
+            }
+        }
+
+        let mut resolved = self.root.clone();
+        let mut exists = true;
+        for component in components {
+            resolved.push(component);
+            // Nothing below a missing entry can be a symlink
+            if !exists {
+                continue;
+            }
+
+            let metadata = match fs::symlink_metadata(&resolved) {
+                Ok(metadata) => metadata,
+                Err(error) if error.kind() == ErrorKind::NotFound => {
+                    exists = false;
+                    continue;
+                }
+                Err(_) => return Err(SandboxError::ResolveFailed),
+            };
+
+            if metadata.file_type().is_symlink() {
+                if self.symlinks == SymlinkPolicy::Deny {
+                    return Err(SandboxError::SymlinkNotAllowed);
+                }
+
+                // A dangling symlink would let a new file be created wherever it points
+                resolved = fs::canonicalize(&resolved).map_err(|_| SandboxError::ResolveFailed)?;
+                if self.symlinks == SymlinkPolicy::WithinRoot && !resolved.starts_with(&self.root) {
+                    return Err(SandboxError::EscapesRoot);
+                }
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    // Like subdir, but the directory is left alone when missing
+    pub fn view(&self, path_str: &str) -> Result<Sandbox, SandboxError> {
+        Ok(Sandbox { root: self.resolve(path_str)?, symlinks: self.symlinks })
+    }
+
+    pub fn subdir(&self, path_str: &str) -> Result<Sandbox, SandboxError> {
+        let root = self.view(path_str)?.root;
+        fs::create_dir_all(&root).map_err(|_| SandboxError::RootNotFound)?;
+        let root = fs::canonicalize(&root).map_err(|_| SandboxError::RootNotFound)?;
+        if !root.is_dir() {
+            return Err(SandboxError::RootNotFound);
+        }
+
+        Ok(Sandbox { root, symlinks: self.symlinks })
+    }
+
+    pub fn get_root(&self) -> &Path { &self.root }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::temp_dir;
+    use super::*;
+
+    #[test]
+    fn resolve_stays_under_the_root() {
+        let dir = temp_dir("sandbox-escape");
+        let sandbox = Sandbox::new(dir.to_str().unwrap(), SymlinkPolicy::Deny).unwrap();
+
+        assert_eq!(sandbox.resolve("a/./b/../c").unwrap(), dir.join("a/c"));
+        assert_eq!(sandbox.resolve("").unwrap(), dir);
+        assert!(matches!(sandbox.resolve(".."), Err(SandboxError::EscapesRoot)));
+        assert!(matches!(sandbox.resolve("a/../../b"), Err(SandboxError::EscapesRoot)));
+        assert!(matches!(sandbox.resolve("/etc/passwd"), Err(SandboxError::AbsolutePath)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_follow_the_policy() {
+        use std::os::unix::fs::symlink;
+
+        let dir = temp_dir("sandbox-symlinks");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("inner")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        symlink(root.join("inner"), root.join("inside_link")).unwrap();
+        symlink(dir.join("outside"), root.join("outside_link")).unwrap();
+        let root_str = root.to_str().unwrap();
+
+        let deny = Sandbox::new(root_str, SymlinkPolicy::Deny).unwrap();
+        assert!(matches!(deny.resolve("inside_link/file"), Err(SandboxError::SymlinkNotAllowed)));
+
+        let within_root = Sandbox::new(root_str, SymlinkPolicy::WithinRoot).unwrap();
+        assert_eq!(within_root.resolve("inside_link/file").unwrap(), root.join("inner/file"));
+        assert!(matches!(within_root.resolve("outside_link/file"), Err(SandboxError::EscapesRoot)));
+
+        let follow = Sandbox::new(root_str, SymlinkPolicy::Follow).unwrap();
+        assert_eq!(follow.resolve("outside_link/file").unwrap(), dir.join("outside/file"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn dangling_symlinks_are_not_followed_out() {
+        use std::os::unix::fs::symlink;
+
+        let dir = temp_dir("sandbox-dangling");
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        symlink(dir.join("missing"), root.join("dangling")).unwrap();
+
+        let within_root = Sandbox::new(root.to_str().unwrap(), SymlinkPolicy::WithinRoot).unwrap();
+        assert!(within_root.resolve("dangling").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn subdir_creates_the_directory() {
+        let dir = temp_dir("sandbox-subdir");
+        let sandbox = Sandbox::new(dir.to_str().unwrap(), SymlinkPolicy::Deny).unwrap();
+
+        assert_eq!(sandbox.subdir("home/alice").unwrap().get_root(), dir.join("home/alice"));
+        assert!(dir.join("home/alice").is_dir());
+        assert!(matches!(sandbox.subdir("../home"), Err(SandboxError::EscapesRoot)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn open_does_not_create_the_root() {
+        let dir = temp_dir("sandbox-open");
+        let root = dir.join("root");
+        assert!(matches!(Sandbox::open(root.to_str().unwrap(), SymlinkPolicy::Deny), Err(SandboxError::RootNotFound)));
+        assert!(!root.exists());
+        assert_eq!(Sandbox::open(dir.to_str().unwrap(), SymlinkPolicy::Deny).unwrap().get_root(), dir);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn view_does_not_create_the_directory() {
+        let dir = temp_dir("sandbox-view");
+        let sandbox = Sandbox::new(dir.to_str().unwrap(), SymlinkPolicy::Deny).unwrap();
+
+        assert_eq!(sandbox.view("home/alice").unwrap().get_root(), dir.join("home/alice"));
+        assert!(!dir.join("home").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
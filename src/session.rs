@@ -1,18 +1,102 @@
 use std::io::Error;
-use super::network::{Client};
-use super::filesystem::{remove_file, FileChunkReader, FileChunkWriter};
+use std::path::{Path, PathBuf};
+use super::network::{Client, NetworkError, MAX_DATAGRAM_SIZE};
+use super::reliability::{Delivery, DeliveryState, Timeout, Timeouts};
+use super::filesystem::{check_free_space, get_fs_entries, is_reserved_name, remove_file, FSEntryKind, FSError,
+                        FileChunkReader, FileChunkWriter, WriteOptions};
 use super::utils::ceil;
 use protocol::context::ProtocolContext;
-use protocol::enums::{FILE_CHUNK_SIZE, Action as ProtocolAction, NextAction as ProtocolNextAction};
-use protocol::{proceed_error, proceed_request, proceed_retry};
-use crc32fast::hash;
-use super::cypher::Cypher;
+use protocol::enums::{FILE_CHUNK_SIZE, EntryKind, FieldType, Action as ProtocolAction,
+                      NextAction as ProtocolNextAction};
+use protocol::limits::Limits;
+use protocol::listing::ListEntry;
+use protocol::{proceed_data_chunk, proceed_denied, proceed_error, proceed_insufficient_storage,
+               proceed_quota_exceeded, proceed_request};
+use log::{debug, error, info, warn};
+use super::cypher::{Cypher, CypherError, CypherOptions, PUBLIC_KEY_SIZE};
+use super::datagram::{associated_data, encode, DatagramError, Header, CLIENT_TO_SERVER, HEADER_SIZE,
+                      SERVER_TO_CLIENT};
+use super::keys::{KeyError, KeyRing};
+use super::sandbox::{Sandbox, SandboxError};
+use super::users::{Identity, Users};
+use super::host_key::HostKey;
+use super::authorized_keys::{client_auth_message, AuthorizedKeys, AuthorizedKeysError};
+use super::access::{check_read_only, AccessError, AccessPolicy};
+use super::roles::Roles;
+use super::quotas::{QuotaError, Quotas, Reservation};
+use protocol::permissions::{Permission, DEFAULT_PERMISSIONS};
+
+// Method, field count, then type, length and data of the first field
+const COMMAND_TYPE_OFFSET: usize = 2;
+const COMMAND_OFFSET: usize = 5;
+const MAX_AUTH_ATTEMPTS: u32 = 3;
+
+#[derive(Debug)]
+pub enum SessionError {
+    Datagram(DatagramError),
+    KeyRejected(KeyError),
+    NoKey,
+    Replayed(u32),
+    AuthMethodDisabled,
+    WrongPassword(String),
+    TooManyAttempts,
+    ClientKeyRejected(AuthorizedKeysError),
+    Access(AccessError),
+    Sandbox(SandboxError),
+    Quota(QuotaError),
+    Storage(FSError),
+    DecryptionFailed(CypherError),
+    EncryptionFailed(CypherError),
+}
+
+impl From<SessionError> for Error {
+    fn from(error: SessionError) -> Error {
+        match error {
+            SessionError::Datagram(error) => Error::from(error),
+            SessionError::KeyRejected(cause) => Error::other(format!("Key rejected: {}", Error::from(cause))),
+            SessionError::NoKey => Error::other("No key selected yet"),
+            SessionError::AuthMethodDisabled => Error::other("Authentication method is not enabled"),
+            SessionError::WrongPassword(name) => Error::other(format!("Wrong password or unknown user {}", name)),
+            SessionError::TooManyAttempts => Error::other("Too many password attempts from this address"),
+            SessionError::ClientKeyRejected(error) => Error::from(error),
+            SessionError::Access(error) => Error::from(error),
+            SessionError::Sandbox(error) => Error::from(error),
+            SessionError::Quota(error) => Error::from(error),
+            SessionError::Storage(error) => Error::from(error),
+            SessionError::Replayed(sequence) => Error::other(format!("Sequence {} was already received", sequence)),
+            SessionError::DecryptionFailed(cause) => Error::other(format!("Decryption failed: {}", Error::from(cause))),
+            SessionError::EncryptionFailed(cause) => Error::other(format!("Encryption failed: {}", Error::from(cause))),
+        }
+    }
+}
 
 enum Action {
     Continue,
     Break,
 }
 
+enum Receive {
+    Request,
+    Skip,
+    Close,
+}
+
+#[derive(Clone)]
+pub struct SessionSettings {
+    pub keys: KeyRing,
+    pub sandbox: Sandbox,
+    pub limits: Limits,
+    pub timeouts: Timeouts,
+    pub cypher_options: CypherOptions,
+    pub host_key: HostKey,
+    pub users: Option<Users>,
+    pub authorized_keys: Option<AuthorizedKeys>,
+    pub access: AccessPolicy,
+    pub roles: Option<Roles>,
+    pub quotas: Quotas,
+    pub write_options: WriteOptions,
+}
+
 enum SessionState {
     None,
     Reading(FileChunkReader),
@@ -21,61 +105,221 @@ enum SessionState {
 
 pub struct Session {
     client: Client,
-    cypher: Cypher,
+    keys: KeyRing,
+    // The first authentic datagram binds the session to its key id
+    key_id: Option<u8>,
+    key_bound: bool,
+    cypher: Option<Cypher>,
+    cypher_options: CypherOptions,
+    host_key: HostKey,
+    // Part of the associated data, 0 until the client learns its id from the handshake
+    bound_session_id: u32,
+    // The storage root, sandbox narrows down to the account's root after auth
+    storage: Sandbox,
+    sandbox: Sandbox,
+    users: Option<Users>,
+    authorized_keys: Option<AuthorizedKeys>,
+    access: AccessPolicy,
+    roles: Option<Roles>,
+    quotas: Quotas,
+    write_options: WriteOptions,
+    identity: Option<Identity>,
+    auth_failures: u32,
     ctx: ProtocolContext,
     state: SessionState,
+    reservation: Option<Reservation>,
+    delivery: DeliveryState,
 
     new_request: bool,
     request: Vec<u8>,
+    rejected: u32,
 }
 
 impl Session {
-    pub fn new(client: Client, session_id: u8, cypher: Cypher) -> Session {
-        Session { client, cypher, ctx: ProtocolContext::new(session_id),
-            state: SessionState::None, new_request: true, request: Vec::new() }
+    pub fn new(client: Client, session_id: u32, settings: SessionSettings) -> Session {
+        let mut ctx = ProtocolContext::new(session_id);
+        ctx.set_limits(settings.limits);
+        ctx.set_redirect_port(client.port());
+        ctx.set_auth_required(settings.users.is_some() || settings.authorized_keys.is_some());
+        Session { client, keys: settings.keys, key_id: None, key_bound: false, cypher: None,
+            cypher_options: settings.cypher_options, host_key: settings.host_key, bound_session_id: 0,
+            storage: settings.sandbox.clone(), sandbox: settings.sandbox, users: settings.users,
+            authorized_keys: settings.authorized_keys, access: settings.access, roles: settings.roles,
+            quotas: settings.quotas, write_options: settings.write_options, identity: None,
+            auth_failures: 0, ctx, state: SessionState::None, reservation: None,
+            delivery: DeliveryState::new(settings.timeouts), new_request: true, request: Vec::new(), rejected: 0 }
     }
 
-    fn encrypted_response(&mut self) -> Vec<u8> {
-        match self.cypher.encrypt(self.ctx.get_response()) {
-            Ok(encrypted_data) => encrypted_data,
-            Err(_) => panic!("Error encrypting response"),
+    fn user_name(&self) -> &str {
+        self.identity.as_ref().map_or("anonymous", |identity| identity.get_name())
+    }
+
+    fn get_permissions(&self, user: &str) -> Vec<Permission> {
+        match &self.roles {
+            Some(roles) => roles.get_permissions(user),
+            None => DEFAULT_PERMISSIONS.to_vec(),
         }
     }
 
-    fn encrypted_response_with_crc(&mut self) -> Vec<u8> {
-        let encrypted_data = &self.encrypted_response()[..];
-        let crc = hash(encrypted_data);
-        [&crc.to_be_bytes()[..], encrypted_data].concat()
+    fn get_user_sandbox(&self, user: &str) -> Result<Sandbox, SandboxError> {
+        match self.access.get_root(user) {
+            Some(root) => self.storage.view(&root),
+            None => Ok(self.storage.clone()),
+        }
     }
 
-    fn send_response(&mut self) -> Action {
-        let response = self.encrypted_response_with_crc();
-        if let Err(error) = self.client.send(&response) {
-            println!("Error while sending response: {}", Error::from(error).to_string());
+    fn check_path(&self, sandbox: &Sandbox, user: &str, permission: Permission) -> Result<PathBuf, SessionError> {
+        let client_path = Path::new(self.ctx.get_file_path());
+        let path = sandbox.resolve(self.ctx.get_file_path()).map_err(SessionError::Sandbox)?;
+        if client_path.components().any(|component| is_reserved_name(component.as_os_str()))
+            || path.file_name().is_some_and(is_reserved_name) {
+            return Err(SessionError::Access(AccessError::ReservedName));
+        }
+
+        // Rules see the resolved path, so a symlink can't lead around them
+        let Ok(relative) = path.strip_prefix(sandbox.get_root()) else {
+            return match self.access.is_unrestricted(user) {
+                true => Ok(path),
+                false => Err(SessionError::Access(AccessError::OutsideRoot)),
+            };
+        };
+        self.access.check(user, permission, relative).map_err(SessionError::Access)?;
+        Ok(path)
+    }
+
+    fn resolve_path(&self, permission: Permission) -> Result<PathBuf, SessionError> {
+        let restrictions = self.identity.as_ref().map(|identity| identity.get_restrictions());
+        let read_only = restrictions.is_some_and(|restrictions| restrictions.read_only);
+        check_read_only(read_only, permission).map_err(SessionError::Access)?;
+
+        let path = self.check_path(&self.sandbox, self.user_name(), permission)?;
+        if let Some(prefix) = restrictions.and_then(|restrictions| restrictions.path_prefix.as_deref())
+            && !path.starts_with(self.sandbox.resolve(prefix).map_err(SessionError::Sandbox)?) {
+            return Err(SessionError::Access(AccessError::OutsidePathPrefix));
+        }
+
+        Ok(path)
+    }
+
+    fn send_request_error(&mut self, error: SessionError) -> Action {
+        let proceed = match error {
+            SessionError::Access(_) => proceed_denied,
+            SessionError::Quota(QuotaError::BytesExceeded(_) | QuotaError::FilesExceeded(_)
+                                | QuotaError::ReservationExceeded) => proceed_quota_exceeded,
+            SessionError::Storage(FSError::InsufficientStorage) => proceed_insufficient_storage,
+            _ => proceed_error,
+        };
+        let err_msg = Error::from(error).to_string();
+        warn!("Error: {}", err_msg);
+        self.ctx.set_err_msg(err_msg);
+        proceed(&mut self.ctx);
+        self.send_response()
+    }
+
+    // None while the session shares the storage root with other accounts
+    fn get_user_root(&self) -> Option<&Path> {
+        Some(self.sandbox.get_root()).filter(|root| *root != self.storage.get_root())
+    }
+
+    fn reserve(&self, path: &Path, bytes: u64) -> Result<Reservation, SessionError> {
+        self.quotas.reserve(self.user_name(), self.get_user_root(), path, bytes).map_err(SessionError::Quota)
+    }
+
+    fn encrypted_response(&mut self, sequence: u32) -> Result<Vec<u8>, SessionError> {
+        let associated_data = associated_data(self.bound_session_id, SERVER_TO_CLIENT, sequence);
+        let cypher = self.cypher.as_mut().ok_or(SessionError::NoKey)?;
+        cypher.encrypt(self.ctx.get_response(), &associated_data).map_err(SessionError::EncryptionFailed)
+    }
+
+    fn encrypted_response_with_crc(&mut self) -> Result<Vec<u8>, SessionError> {
+        let key_id = self.key_id.ok_or(SessionError::NoKey)?;
+        let sequence = self.delivery.next_sequence();
+        Ok(encode(key_id, self.bound_session_id, sequence, &self.encrypted_response(sequence)?))
+    }
+
+    fn send_datagram(&mut self, datagram: &[u8]) -> Action {
+        if let Err(error) = self.client.send(datagram) {
+            error!("Error while sending response: {}", Error::from(error));
             return Action::Break;
         }
 
         Action::Continue
     }
 
+    fn send_response(&mut self) -> Action {
+        let response = match self.encrypted_response_with_crc() {
+            Ok(response) => response,
+            Err(error) => {
+                error!("Error while sending response: {}", Error::from(error));
+                return Action::Break;
+            }
+        };
+        let action = self.send_datagram(&response);
+        self.delivery.record(response);
+        action
+    }
+
+    fn resend(&mut self) -> Action {
+        for datagram in self.delivery.get_datagrams().to_vec() {
+            if let Action::Break = self.send_datagram(&datagram) {
+                return Action::Break;
+            }
+        }
+
+        Action::Continue
+    }
+
+    // Field data may hold credentials, so only the header is logged
+    fn log_rejected_request(&self) {
+        let method = self.request.first();
+        let command = match self.request.get(COMMAND_TYPE_OFFSET) {
+            Some(&field_type) if field_type == FieldType::Command as u8 => self.request.get(COMMAND_OFFSET),
+            _ => None,
+        };
+        debug!("Session {}: rejected request, method {:?}, command {:?}, {} bytes", self.ctx.get_session_id(),
+               method, command, self.request.len());
+    }
+
     fn handle_send_error(&mut self) -> Action {
-        println!("Error: {}", self.ctx.get_err_msg());
-        self.send_response()
+        warn!("Error: {}", self.ctx.get_err_msg());
+        if let Action::Break = self.send_response() {
+            return Action::Break;
+        }
+
+        self.new_request = true;
+        Action::Continue
     }
     fn handle_fileinfo_write(&mut self) -> Action {
-        let writer = match FileChunkWriter::new(self.ctx.get_file_path()) {
+        let path = match self.resolve_path(Permission::Write) {
+            Ok(path) => path,
+            Err(error) => return self.send_request_error(error),
+        };
+
+        let mut reservation = match self.reserve(&path, self.ctx.get_file_size()) {
+            Ok(reservation) => reservation,
+            Err(error) => return self.send_request_error(error),
+        };
+
+        if let Err(error) = check_free_space(&path, self.ctx.get_file_size(), &self.write_options) {
+            return self.send_request_error(SessionError::Storage(error));
+        }
+
+        info!("Session {}: {} uploads {}", self.ctx.get_session_id(), self.user_name(), self.ctx.get_file_path());
+        let preallocate = self.write_options.get_preallocate();
+        let mut writer = match FileChunkWriter::new(&path, self.ctx.get_file_size(), FILE_CHUNK_SIZE as usize,
+                                                    preallocate) {
             Ok(writer) => writer,
-            Err(error) => {
-                let err_msg = Error::from(error).to_string().as_str().to_owned();
-                println!("Error: {}", err_msg);
-                self.ctx.set_err_msg(err_msg);
-                proceed_error(&mut self.ctx);
-                return self.send_response();
-            }
+            Err(error) => return self.send_request_error(SessionError::Storage(error)),
         };
+
+        // Preallocated bytes are on disk already
+        if let Err(error) = reservation.consume(writer.get_length()) {
+            let _ = writer.discard();
+            return self.send_request_error(SessionError::Quota(error));
+        }
         self.state = SessionState::Writing(writer);
+        self.reservation = Some(reservation);
 
-        // self.ctx.increment_current_chunk_id();
         let chunk_count = ceil(self.ctx.get_file_size(), FILE_CHUNK_SIZE as u64);
         self.ctx.set_chunk_count(chunk_count);
         self.ctx.set_file_open(true);
@@ -83,38 +327,283 @@ impl Session {
         Action::Continue
     }
 
+    fn handle_fileinfo_resume(&mut self) -> Action {
+        let path = match self.resolve_path(Permission::Write) {
+            Ok(path) => path,
+            Err(error) => return self.send_request_error(error),
+        };
+
+        info!("Session {}: {} resumes {}", self.ctx.get_session_id(), self.user_name(), self.ctx.get_file_path());
+        let writer = match FileChunkWriter::resume(&path, self.ctx.get_file_size(), FILE_CHUNK_SIZE as usize) {
+            Ok(writer) => writer,
+            Err(error) => return self.send_request_error(SessionError::Storage(error)),
+        };
+
+        let remaining = self.ctx.get_file_size().saturating_sub(writer.get_length());
+        let reservation = match self.reserve(&path, remaining) {
+            Ok(reservation) => reservation,
+            Err(error) => return self.send_request_error(error),
+        };
+
+        if let Err(error) = check_free_space(&path, remaining, &self.write_options) {
+            return self.send_request_error(SessionError::Storage(error));
+        }
+
+        let chunk_count = ceil(self.ctx.get_file_size(), FILE_CHUNK_SIZE as u64);
+        self.ctx.set_chunk_count(chunk_count);
+        self.ctx.set_received_chunks(writer.get_received_chunks().clone());
+        self.state = SessionState::Writing(writer);
+        self.reservation = Some(reservation);
+        self.ctx.set_file_open(true);
+        self.new_request = false;
+        Action::Continue
+    }
+
     fn handle_fileinfo_read(&mut self) -> Action {
-        let reader = match FileChunkReader::new(self.ctx.get_file_path(), FILE_CHUNK_SIZE as usize) {
+        let path = match self.resolve_path(Permission::Read) {
+            Ok(path) => path,
+            Err(error) => return self.send_request_error(error),
+        };
+
+        info!("Session {}: {} downloads {}", self.ctx.get_session_id(), self.user_name(), self.ctx.get_file_path());
+        let mut reader = match FileChunkReader::new(&path, FILE_CHUNK_SIZE as usize) {
             Ok(reader) => reader,
-            Err(error) => {
-                let err_msg = Error::from(error).to_string().as_str().to_owned();
-                println!("Error: {}", err_msg);
+            Err(error) => return self.send_request_error(SessionError::Storage(error)),
+        };
+
+        let file_size = match reader.get_size() {
+            Ok(file_size) => file_size,
+            Err(error) => return self.send_request_error(SessionError::Storage(error)),
+        };
+        let range_length = self.ctx.get_range_length().unwrap_or(file_size);
+        let length = match reader.set_range(self.ctx.get_range_offset(), range_length) {
+            Ok(length) => length,
+            Err(error) => return self.send_request_error(SessionError::Storage(error)),
+        };
+        self.state = SessionState::Reading(reader);
+
+        self.ctx.set_file_size(file_size);
+        self.ctx.set_range_length(Some(length));
+        let chunk_count = ceil(length, FILE_CHUNK_SIZE as u64);
+        self.ctx.set_chunk_count(chunk_count);
+        self.ctx.set_file_open(true);
+        self.new_request = false;
+        Action::Continue
+    }
+
+    fn handle_list_read(&mut self) -> Action {
+        let path = match self.resolve_path(Permission::List) {
+            Ok(path) => path,
+            Err(error) => return self.send_request_error(error),
+        };
+
+        info!("Session {}: {} lists {}", self.ctx.get_session_id(), self.user_name(), self.ctx.get_file_path());
+        let fs_entries = match get_fs_entries(&path) {
+            Ok(entries) => entries,
+            Err(error) => return self.send_request_error(SessionError::Storage(error)),
+        };
+
+        let entries = fs_entries.iter().map(|entry| {
+            let kind = match entry.get_kind() {
+                FSEntryKind::File => EntryKind::File,
+                FSEntryKind::Directory => EntryKind::Directory,
+                FSEntryKind::Symlink => EntryKind::Symlink,
+                FSEntryKind::Other => EntryKind::Other,
+            };
+            ListEntry::new(kind, entry.get_name().to_string_lossy().into_owned(), entry.get_size(),
+                           entry.get_modified())
+        }).collect();
+
+        self.ctx.set_list_entries(entries);
+        self.ctx.set_file_open(true);
+        self.new_request = false;
+        Action::Continue
+    }
+
+    fn handle_delete(&mut self) -> Action {
+        let path = match self.resolve_path(Permission::Delete) {
+            Ok(path) => path,
+            Err(error) => return self.send_request_error(error),
+        };
+
+        info!("Session {}: {} deletes {}", self.ctx.get_session_id(), self.user_name(), self.ctx.get_file_path());
+        if let Err(error) = remove_file(&path) {
+            return self.send_request_error(SessionError::Storage(error));
+        }
+
+        self.ctx.set_file_open(true);
+        self.new_request = false;
+        Action::Continue
+    }
+
+    fn handle_usage(&mut self) -> Action {
+        let path = match self.resolve_path(Permission::List) {
+            Ok(path) => path,
+            Err(error) => return self.send_request_error(error),
+        };
+
+        match self.quotas.get_usage(self.user_name(), self.get_user_root(), &path) {
+            Ok(usage) => self.ctx.set_usage(usage),
+            Err(error) => return self.send_request_error(SessionError::Quota(error)),
+        }
+
+        self.ctx.set_file_open(true);
+        self.new_request = false;
+        Action::Continue
+    }
+
+    fn handle_permissions(&mut self) -> Action {
+        let user = self.ctx.get_query_user().to_string();
+        let sandbox = match self.get_user_sandbox(&user) {
+            Ok(sandbox) => sandbox,
+            Err(error) => return self.send_request_error(SessionError::Sandbox(error)),
+        };
+        let permissions = self.get_permissions(&user).into_iter()
+            .filter(|permission| self.check_path(&sandbox, &user, *permission).is_ok())
+            .collect();
+
+        info!("Session {}: {} queries permissions of {} on {}", self.ctx.get_session_id(), self.user_name(), user,
+              self.ctx.get_file_path());
+        self.ctx.set_effective_permissions(permissions);
+        self.ctx.set_file_open(true);
+        self.new_request = false;
+        Action::Continue
+    }
+
+    fn handle_handshake(&mut self) -> Action {
+        let peer_public_key = match <[u8; PUBLIC_KEY_SIZE]>::try_from(self.ctx.get_peer_public_key()) {
+            Ok(public_key) => public_key,
+            Err(_) => {
+                let err_msg = "Not valid public key length".to_string();
+                warn!("Error: {}", err_msg);
                 self.ctx.set_err_msg(err_msg);
                 proceed_error(&mut self.ctx);
                 return self.send_response();
             }
         };
 
-        let file_size = match reader.get_size() {
-            Ok(file_size) => file_size,
+        let offered_suites = self.ctx.get_offered_suites().to_vec();
+        let handshake = match &mut self.cypher {
+            Some(cypher) => cypher.negotiate(&offered_suites)
+                .and_then(|suite| Ok((cypher.handshake(&peer_public_key, &offered_suites, suite)?, suite)))
+                .map_err(Error::from),
+            None => Err(Error::from(SessionError::NoKey)),
+        };
+        match handshake {
+            Ok((public_key, suite)) => {
+                debug!("Session {} uses {}", self.ctx.get_session_id(), suite.get_name());
+                self.ctx.set_public_key(public_key.to_vec());
+                self.ctx.set_cipher_suite(suite.get_name().to_string());
+            }
             Err(error) => {
-                let err_msg = Error::from(error).to_string().as_str().to_owned();
-                println!("Error: {}", err_msg);
+                let err_msg = error.to_string();
+                warn!("Error: {}", err_msg);
                 self.ctx.set_err_msg(err_msg);
                 proceed_error(&mut self.ctx);
                 return self.send_response();
             }
+        }
+
+        // Proves to the client that it talks to the holder of the host key, not just of the shared key
+        if let Some(transcript) = self.cypher.as_ref().and_then(|cypher| cypher.get_transcript()) {
+            let signature = self.host_key.sign(transcript);
+            self.ctx.set_host_signature(self.host_key.get_public_key().to_vec(), signature.to_vec());
+        }
+
+        self.new_request = false;
+        Action::Continue
+    }
+
+    fn handle_secured(&mut self) -> Action {
+        if let Action::Break = self.send_response() {
+            return Action::Break;
+        }
+
+        if let Some(cypher) = &mut self.cypher {
+            cypher.commit();
+        }
+        self.bound_session_id = self.ctx.get_session_id();
+        self.client.redirect();
+        self.new_request = true;
+        Action::Continue
+    }
+
+    fn verify_password(&self) -> Result<Identity, SessionError> {
+        let users = self.users.as_ref().ok_or(SessionError::AuthMethodDisabled)?;
+        if !users.allow_attempt(self.client.peer_addr().ip()) {
+            return Err(SessionError::TooManyAttempts);
+        }
+
+        let username = self.ctx.get_username();
+        users.verify(username, self.ctx.get_password()).ok_or(SessionError::WrongPassword(username.to_string()))
+    }
+
+    fn verify_client_key(&self) -> Result<Identity, SessionError> {
+        let authorized_keys = self.authorized_keys.as_ref().ok_or(SessionError::AuthMethodDisabled)?;
+        let transcript = self.cypher.as_ref().and_then(|cypher| cypher.get_transcript()).ok_or(SessionError::NoKey)?;
+        authorized_keys.verify(self.ctx.get_client_key(), self.ctx.get_signature(), &client_auth_message(transcript),
+                               self.client.peer_addr().ip())
+            .map_err(SessionError::ClientKeyRejected)
+    }
+
+    fn handle_auth(&mut self) -> Action {
+        let verified = match self.ctx.get_client_key().is_empty() {
+            true => self.verify_password(),
+            false => self.verify_client_key(),
+        };
+        let identity = match verified {
+            Ok(identity) => identity,
+            Err(error) => {
+                self.auth_failures += 1;
+                warn!("Session {}: {}", self.ctx.get_session_id(), Error::from(error));
+                self.ctx.set_err_msg("Authentication failed".to_string());
+                proceed_error(&mut self.ctx);
+                let action = self.send_response();
+                self.ctx.reset();
+                if self.auth_failures >= MAX_AUTH_ATTEMPTS {
+                    warn!("Session {}: too many failed authentications", self.ctx.get_session_id());
+                    return Action::Break;
+                }
+                return action;
+            }
         };
-        self.state = SessionState::Reading(reader);
 
-        self.ctx.set_file_size(file_size);
-        let chunk_count = ceil(file_size, FILE_CHUNK_SIZE as u64);
-        self.ctx.set_chunk_count(chunk_count);
-        self.ctx.set_file_open(true);
+        if let Some(root) = self.access.get_root(identity.get_name()) {
+            self.sandbox = match self.storage.subdir(&root) {
+                Ok(sandbox) => sandbox,
+                Err(error) => {
+                    let err_msg = format!("Root of {} unavailable: {}", identity.get_name(), Error::from(error));
+                    warn!("Error: {}", err_msg);
+                    self.ctx.set_err_msg(err_msg);
+                    proceed_error(&mut self.ctx);
+                    let action = self.send_response();
+                    self.ctx.reset();
+                    return action;
+                }
+            };
+        }
+
+        info!("Session {} authenticated as {}", self.ctx.get_session_id(), identity.get_name());
+        self.identity = Some(identity);
+        self.ctx.set_auth_verified();
         self.new_request = false;
         Action::Continue
     }
 
+    fn handle_done(&mut self) -> Action {
+        if let Action::Break = self.send_response() {
+            return Action::Break;
+        }
+
+        self.new_request = true;
+        Action::Continue
+    }
+
+    fn handle_denied(&mut self) -> Action {
+        warn!("Session {}: {} denied: {}", self.ctx.get_session_id(), self.user_name(), self.ctx.get_err_msg());
+        self.handle_done()
+    }
+
     fn handle_terminate(&mut self) -> Action {
         match self.send_response() {
             Action::Break => Action::Break,
@@ -131,17 +620,11 @@ impl Session {
             if let Some(value) = reader.next() {
                 match value {
                     Ok(chunk) => self.ctx.set_data_chunk(chunk),
-                    Err(error) => {
-                        let err_msg = Error::from(error).to_string().as_str().to_owned();
-                        println!("Error: {}", err_msg);
-                        self.ctx.set_err_msg(err_msg);
-                        proceed_error(&mut self.ctx);
-                        return self.send_response();
-                    }
+                    Err(error) => return self.send_request_error(SessionError::Storage(error)),
                 }
             } else {
                 let err_msg = "ChunkReader returned None".to_string();
-                println!("Error: {}", err_msg);
+                warn!("Error: {}", err_msg);
                 self.ctx.set_err_msg(err_msg);
                 proceed_error(&mut self.ctx);
                 return self.send_response();
@@ -156,50 +639,74 @@ impl Session {
         Action::Continue
     }
 
-    fn handle_write_data(&mut self) -> Action {
+    fn handle_send_window(&mut self) -> Action {
         if let Action::Break = self.send_response() {
             return Action::Break;
         }
 
-        if let SessionState::Writing(writer) = &mut self.state {
-            match writer.write_chunk(self.ctx.get_data_chunk()) {
-                Ok(()) => (),
+        for chunk_id in self.ctx.get_pending_chunks().to_vec() {
+            let chunk = match &mut self.state {
+                SessionState::Reading(reader) => reader.read_chunk(chunk_id),
+                _ => break,
+            };
+
+            match chunk {
+                Ok(chunk) => proceed_data_chunk(&mut self.ctx, chunk_id, chunk),
                 Err(error) => {
-                    let err_msg = Error::from(error).to_string().as_str().to_owned();
-                    println!("Error: {}", err_msg);
-                    self.ctx.set_err_msg(err_msg);
-                    proceed_error(&mut self.ctx);
-                    return self.send_response();
+                    self.new_request = true;
+                    return self.send_request_error(SessionError::Storage(error));
                 }
-            };
+            }
+
+            if let Action::Break = self.send_response() {
+                return Action::Break;
+            }
+        }
+
+        if !self.new_request && self.ctx.get_started() {
+            self.new_request = true;
         }
 
-        self.ctx.increment_current_chunk_id();
         Action::Continue
     }
 
-    fn handle_end(&mut self) -> Action {
+    fn handle_write_data(&mut self) -> Action {
         if let Action::Break = self.send_response() {
             return Action::Break;
         }
 
+        let chunk_id = self.ctx.get_current_chunk_id();
         if let SessionState::Writing(writer) = &mut self.state {
-            match writer.finish() {
-                Ok(_) => (),
-                Err(error) => {
-                    let err_msg = Error::from(error).to_string().as_str().to_owned();
-                    println!("Error: {}", err_msg);
-                    self.ctx.set_err_msg(err_msg);
-                    proceed_error(&mut self.ctx);
-                    return self.send_response();
-                }
+            let growth = writer.get_growth(chunk_id, self.ctx.get_data_chunk().len());
+            if let Some(reservation) = &mut self.reservation && let Err(error) = reservation.consume(growth) {
+                return self.send_request_error(SessionError::Quota(error));
+            }
+
+            match writer.write_at(chunk_id, self.ctx.get_data_chunk()) {
+                Ok(()) => (),
+                Err(error) => return self.send_request_error(SessionError::Storage(error)),
             };
         }
 
+        self.ctx.set_chunk_received(chunk_id);
+        Action::Continue
+    }
+
+    fn handle_end(&mut self) -> Action {
+        // The client hears about the upload once it is in place, the part file stays for a later attempt otherwise
+        let finished = match &mut self.state {
+            SessionState::Writing(writer) => writer.finish(),
+            _ => Ok(()),
+        };
+        let action = match finished {
+            Ok(()) => self.send_response(),
+            Err(error) => self.send_request_error(SessionError::Storage(error)),
+        };
+
         self.state = SessionState::None;
+        self.reservation = None;
         self.ctx.reset();
-
-        Action::Continue
+        action
     }
 
     fn handle_cancel(&mut self) -> Action {
@@ -207,38 +714,24 @@ impl Session {
             return Action::Break;
         }
 
-        let mut is_writer = false;
         if let SessionState::Writing(writer) = &mut self.state {
-            match writer.finish() {
-                Ok(_) => { is_writer = true; },
-                Err(error) => {
-                    let err_msg = Error::from(error).to_string().as_str().to_owned();
-                    println!("Error: {}", err_msg);
-                    self.ctx.set_err_msg(err_msg);
-                    proceed_error(&mut self.ctx);
-                    return self.send_response();
-                }
+            match writer.discard() {
+                Ok(_) => (),
+                Err(error) => return self.send_request_error(SessionError::Storage(error)),
             }
         };
 
         self.state = SessionState::None;
-        if is_writer {
-            match remove_file(self.ctx.get_file_path()) {
-                Ok(_) => (),
-                Err(error) => {
-                    let err_msg = Error::from(error).to_string().as_str().to_owned();
-                    println!("Error: {}", err_msg);
-                    self.ctx.set_err_msg(err_msg);
-                    proceed_error(&mut self.ctx);
-                    return self.send_response();
-                }
-            }
-        }
-
+        self.reservation = None;
         self.ctx.reset();
         Action::Continue
     }
 
+    fn handle_resend(&mut self) -> Action {
+        self.delivery.restore_previous();
+        self.resend()
+    }
+
     fn handle_none(&mut self) -> Action {
         if let Action::Break = self.send_response() {
             return Action::Break;
@@ -251,58 +744,128 @@ impl Session {
         Action::Continue
     }
 
+    fn handle_timeout(&mut self) -> Receive {
+        match self.delivery.on_timeout() {
+            Timeout::Retransmit => match self.resend() {
+                Action::Continue => Receive::Skip,
+                Action::Break => Receive::Close,
+            },
+            Timeout::Wait => Receive::Skip,
+            Timeout::Expired => {
+                info!("Session {} timed out", self.ctx.get_session_id());
+                Receive::Close
+            }
+        }
+    }
+
+    // A bad datagram is logged and counted but never answered, the session keeps waiting for a good one
+    fn reject(&mut self, error: SessionError) -> Receive {
+        self.rejected += 1;
+        warn!("Rejected datagram: {}", Error::from(error));
+        Receive::Skip
+    }
+
+    // Retired keys are refused on every datagram, so sessions end once the grace period is over
+    fn select_key(&mut self, key_id: u8) -> Result<(), KeyError> {
+        let key = self.keys.get(key_id)?;
+        if self.key_bound && self.key_id != Some(key_id) {
+            return Err(KeyError::Mismatch(key_id));
+        }
+
+        if self.key_id != Some(key_id) {
+            self.cypher = Some(Cypher::new(key, self.cypher_options.clone()));
+            self.key_id = Some(key_id);
+        }
+
+        Ok(())
+    }
+
+    fn receive_request(&mut self) -> Receive {
+        let mut buffer = vec![0u8; MAX_DATAGRAM_SIZE];
+        let size = match self.client.recv(&mut buffer, self.delivery.get_timeout()) {
+            Ok(size) => size,
+            Err(NetworkError::Timeout) => return self.handle_timeout(),
+            Err(error) => {
+                error!("Error while receiving response: {}", Error::from(error));
+                return Receive::Close;
+            }
+        };
+
+        let header = match Header::parse(&buffer[..size]) {
+            Ok(header) => header,
+            Err(error) => return self.reject(SessionError::Datagram(error)),
+        };
+
+        if let Err(error) = self.select_key(header.get_key_id()) {
+            return self.reject(SessionError::KeyRejected(error));
+        }
+
+        let sequence = header.get_sequence();
+        let delivery = self.delivery.check(sequence);
+        if let Delivery::Replayed = delivery {
+            return self.reject(SessionError::Replayed(sequence));
+        }
+
+        let associated_data = associated_data(self.bound_session_id, CLIENT_TO_SERVER, sequence);
+        let decrypted = match &mut self.cypher {
+            Some(cypher) => cypher.decrypt(header.get_nonce(), &buffer[HEADER_SIZE..size], &associated_data),
+            None => return self.reject(SessionError::NoKey),
+        };
+        self.request = match decrypted {
+            Ok(data) => data,
+            Err(error) => return self.reject(SessionError::DecryptionFailed(error)),
+        };
+        self.key_bound = true;
+
+        // Only authentic datagrams are answered or move the replay window
+        if let Delivery::Duplicate = delivery {
+            return match self.resend() {
+                Action::Continue => Receive::Skip,
+                Action::Break => Receive::Close,
+            };
+        }
+
+        self.delivery.accept(sequence);
+        Receive::Request
+    }
+
     pub fn start(&mut self) {
         loop {
             if self.new_request {
-                let mut buffer = [0u8; FILE_CHUNK_SIZE as usize + 1500];
-                let size = match self.client.recv(&mut buffer) {
-                    Ok(size) => size,
-                    Err(error) => {
-                        println!("Error while receiving response: {}", Error::from(error).to_string());
-                        break;
-                    }
-                };
-
-                let crc_bytes: &[u8; 4] = match <&[u8; 4]>::try_from(&buffer[..4]) {
-                    Ok(crc) => crc,
-                    Err(error) => panic!("Error while parsing CRC: {}", error),
-                };
-                let crc = u32::from_be_bytes(*crc_bytes);
-                let excepted_crc = hash(&buffer[4..size]);
-                if crc != excepted_crc {
-                    println!("CRC mismatch");
-                    proceed_retry(&mut self.ctx);
-                    if let Action::Break = self.send_response() {
-                        break;
-                    };
+                match self.receive_request() {
+                    Receive::Request => (),
+                    Receive::Skip => continue,
+                    Receive::Close => break,
                 }
 
-                let nonce: &[u8; 12] = match <&[u8; 12]>::try_from(&buffer[4..16]) {
-                    Ok(nonce) => nonce,
-                    Err(error) => panic!("Error while parsing nonce: {}", error),
-                };
-
-                self.request = match self.cypher.decrypt(nonce, &buffer[16..size]) {
-                    Ok(data) => data,
-                    Err(error) => panic!("Error: {}", Error::from(error).to_string()),
-                };
+                self.ctx.set_permissions(self.get_permissions(self.user_name()));
             }
-            
+
             let action = match proceed_request(&mut self.ctx, &self.request) {
                 ProtocolAction::SendError => {
-                    self.handle_send_error();
-                    println!("Len: {}", self.request.len());
-                    println!("{:?}", self.request);
-                    break;
+                    self.log_rejected_request();
+                    self.handle_send_error()
                 }
                 ProtocolAction::RequestFileInfoRead => { self.handle_fileinfo_read() },
                 ProtocolAction::RequestFileInfoWrite => { self.handle_fileinfo_write() },
+                ProtocolAction::RequestFileInfoResume => { self.handle_fileinfo_resume() },
+                ProtocolAction::RequestListing => { self.handle_list_read() },
+                ProtocolAction::RequestHandShake => { self.handle_handshake() },
+                ProtocolAction::RequestAuth => { self.handle_auth() },
+                ProtocolAction::RequestDelete => { self.handle_delete() },
+                ProtocolAction::RequestPermissions => { self.handle_permissions() },
+                ProtocolAction::RequestUsage => { self.handle_usage() },
                 ProtocolAction::SendResponse(response) => match response {
                     ProtocolNextAction::Terminate => { self.handle_terminate() },
                     ProtocolNextAction::ReadData => { self.handle_read_data() },
                     ProtocolNextAction::WriteData => { self.handle_write_data() },
                     ProtocolNextAction::End => { self.handle_end() },
                     ProtocolNextAction::Cancel => { self.handle_cancel() },
+                    ProtocolNextAction::HandShake => { self.handle_secured() },
+                    ProtocolNextAction::Resend => { self.handle_resend() },
+                    ProtocolNextAction::SendWindow => { self.handle_send_window() },
+                    ProtocolNextAction::Done => { self.handle_done() },
+                    ProtocolNextAction::Denied => { self.handle_denied() },
                     ProtocolNextAction::None => { self.handle_none() },
                 },
             };
@@ -312,5 +875,20 @@ impl Session {
                 Action::Break => break,
             }
         }
+
+        if self.rejected > 0 {
+            info!("Session {} rejected {} datagrams", self.ctx.get_session_id(), self.rejected);
+        }
+
+        // An unfinished upload stays on disk so the client can continue it later, one without any chunk is dropped
+        if let SessionState::Writing(writer) = &mut self.state {
+            let result = match writer.get_received_chunks().get_count() {
+                0 => writer.discard(),
+                _ => writer.suspend(),
+            };
+            if let Err(error) = result {
+                error!("Error while saving upload state: {}", Error::from(error));
+            }
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Error;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use argon2::Argon2;
+use ipnet::IpNet;
+use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use password_hash::rand_core::OsRng;
+use super::rate_limit::RateLimiter;
+
+const MAX_ATTEMPTS_PER_ADDR: u32 = 10;
+const ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum UserError {
+    ReadFailed(String),
+    InvalidLine(usize),
+    InvalidHash(String),
+    DuplicateUser(String),
+    HashFailed,
+}
+
+impl From<UserError> for Error {
+    fn from(error: UserError) -> Error {
+        match error {
+            UserError::ReadFailed(path) => Error::other(format!("Users file {} read failed", path)),
+            UserError::InvalidLine(line) => Error::other(format!("Users file line {} should be name:hash", line)),
+            UserError::InvalidHash(name) => Error::other(format!("User {} has an invalid password hash", name)),
+            UserError::DuplicateUser(name) => Error::other(format!("User {} is listed twice", name)),
+            UserError::HashFailed => Error::other("Password hashing failed"),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Restrictions {
+    pub read_only: bool,
+    // Relative to the user's root, which is the storage root for users without one of their own
+    pub path_prefix: Option<String>,
+    pub sources: Vec<IpNet>,
+}
+
+impl Restrictions {
+    pub fn allows_source(&self, source: IpAddr) -> bool {
+        self.sources.is_empty() || self.sources.iter().any(|net| net.contains(&source))
+    }
+}
+
+pub struct Identity {
+    name: String,
+    restrictions: Restrictions,
+}
+
+impl Identity {
+    pub fn new(name: String, restrictions: Restrictions) -> Self {
+        Identity { name, restrictions }
+    }
+
+    pub fn get_name(&self) -> &str { &self.name }
+    pub fn get_restrictions(&self) -> &Restrictions { &self.restrictions }
+}
+
+// PHC string, e.g. $argon2id$v=19$m=19456,t=2,p=1$salt$hash
+pub fn hash_password(password: &str) -> Result<String, UserError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt).map_err(|_| UserError::HashFailed)?;
+    Ok(hash.to_string())
+}
+
+// Accounts by name, once a users file is configured every session has to authenticate
+#[derive(Clone)]
+pub struct Users {
+    hashes: HashMap<String, String>,
+    // Checked for unknown names, so they take as long as a wrong password
+    dummy_hash: String,
+    // Shared by all sessions, every Argon2 check costs the server real time and memory
+    attempts: Arc<Mutex<RateLimiter>>,
+}
+
+impl Users {
+    // One name:hash per line, blank lines and lines starting with # are skipped
+    pub fn load(path: &Path) -> Result<Self, UserError> {
+        let users_str = fs::read_to_string(path).map_err(|_| UserError::ReadFailed(path.display().to_string()))?;
+        let mut hashes = HashMap::new();
+        for (index, line) in users_str.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (name, hash) = line.split_once(':').ok_or(UserError::InvalidLine(index + 1))?;
+            if name.is_empty() {
+                return Err(UserError::InvalidLine(index + 1));
+            }
+            if PasswordHash::new(hash).is_err() {
+                return Err(UserError::InvalidHash(name.to_string()));
+            }
+            if hashes.insert(name.to_string(), hash.to_string()).is_some() {
+                return Err(UserError::DuplicateUser(name.to_string()));
+            }
+        }
+
+        let attempts = Arc::new(Mutex::new(RateLimiter::new(MAX_ATTEMPTS_PER_ADDR, ATTEMPT_WINDOW)));
+        Ok(Users { hashes, dummy_hash: hash_password("")?, attempts })
+    }
+
+    // Asked before every verify, so guessing from one address is slowed down to a few tries per window
+    pub fn allow_attempt(&self, source: IpAddr) -> bool {
+        self.attempts.lock().is_ok_and(|mut attempts| attempts.allow(source))
+    }
+
+    pub fn verify(&self, name: &str, password: &str) -> Option<Identity> {
+        let (hash, known) = match self.hashes.get(name) {
+            Some(hash) => (hash, true),
+            None => (&self.dummy_hash, false),
+        };
+        let verified = match PasswordHash::new(hash) {
+            Ok(hash) => Argon2::default().verify_password(password.as_bytes(), &hash).is_ok(),
+            Err(_) => false,
+        };
+
+        match known && verified {
+            true => Some(Identity::new(name.to_string(), Restrictions::default())),
+            false => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::temp_dir;
+    use super::*;
+
+    fn load(name: &str, users_str: &str) -> Result<Users, UserError> {
+        let dir = temp_dir(name);
+        let path = dir.join("users");
+        fs::write(&path, users_str).unwrap();
+        let users = Users::load(&path);
+        fs::remove_dir_all(dir).unwrap();
+        users
+    }
+
+    #[test]
+    fn passwords_are_checked_against_argon2_hashes() {
+        let hash = hash_password("secret").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        let users = load("users-verify", &format!("# accounts\n\nalice:{}\n", hash)).unwrap();
+
+        assert_eq!(users.verify("alice", "secret").unwrap().get_name(), "alice");
+        assert!(users.verify("alice", "wrong").is_none());
+        assert!(users.verify("alice", "").is_none());
+    }
+
+    #[test]
+    fn unknown_users_are_rejected_after_the_dummy_check() {
+        let users = load("users-unknown", &format!("alice:{}\n", hash_password("secret").unwrap())).unwrap();
+        assert!(PasswordHash::new(&users.dummy_hash).is_ok());
+
+        assert!(users.verify("bob", "secret").is_none());
+        // The dummy hash is of the empty password, it must not let anyone in either
+        assert!(users.verify("bob", "").is_none());
+    }
+
+    #[test]
+    fn attempts_are_limited_per_address_across_sessions() {
+        let users = load("users-attempts", "").unwrap();
+        let session = users.clone();
+        let source = IpAddr::from([192, 0, 2, 1]);
+        for _ in 0..MAX_ATTEMPTS_PER_ADDR {
+            assert!(session.allow_attempt(source));
+        }
+
+        assert!(!users.allow_attempt(source));
+        assert!(users.allow_attempt(IpAddr::from([192, 0, 2, 2])));
+    }
+
+    #[test]
+    fn malformed_users_files_are_rejected() {
+        assert!(matches!(load("users-no-hash", "alice\n"), Err(UserError::InvalidLine(1))));
+        assert!(matches!(load("users-no-name", "# users\n:$argon2id$\n"), Err(UserError::InvalidLine(2))));
+        assert!(matches!(load("users-plain", "alice:secret\n"), Err(UserError::InvalidHash(_))));
+
+        let hash = hash_password("secret").unwrap();
+        assert!(matches!(load("users-twice", &format!("alice:{}\nalice:{}\n", hash, hash)),
+                         Err(UserError::DuplicateUser(_))));
+    }
+}